@@ -0,0 +1,3 @@
+-- Message of the same chat this one replies to
+ALTER TABLE messages ADD COLUMN reply_to UUID;
+CREATE INDEX messages_reply_to_idx ON messages (reply_to);
@@ -0,0 +1,3 @@
+-- Last message the user has seen in the chat and when they marked it read
+ALTER TABLE chat_user ADD COLUMN last_read_message_id UUID;
+ALTER TABLE chat_user ADD COLUMN last_read_at TIMESTAMP WITH TIME ZONE;
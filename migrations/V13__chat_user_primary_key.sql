@@ -0,0 +1,5 @@
+-- Connecting twice used to insert the same member twice
+DELETE FROM chat_user a USING chat_user b
+WHERE a.chat_id = b.chat_id AND a.user_id = b.user_id AND a.ctid < b.ctid;
+
+ALTER TABLE chat_user ADD PRIMARY KEY (chat_id, user_id);
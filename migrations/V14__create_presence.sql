@@ -0,0 +1,11 @@
+-- One row per live connection to a chat, owned by the server instance holding the socket
+CREATE TABLE presence (
+    connection_id UUID PRIMARY KEY,
+    chat_id UUID NOT NULL,
+    user_id UUID NOT NULL,
+    instance_id VARCHAR(255) NOT NULL,
+    connected_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX presence_chat_id_user_id_idx ON presence (chat_id, user_id);
+CREATE INDEX presence_instance_id_idx ON presence (instance_id);
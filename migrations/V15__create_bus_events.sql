@@ -0,0 +1,8 @@
+-- Events too large for a NOTIFY payload, listeners load them by id
+CREATE TABLE bus_events (
+    id BIGSERIAL PRIMARY KEY,
+    payload TEXT NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX bus_events_created_at_idx ON bus_events (created_at);
@@ -0,0 +1,4 @@
+-- Direct chats only ever have their two users, who may join a group chat is up to its public flag (V22)
+ALTER TABLE chats ADD COLUMN kind VARCHAR(16) NOT NULL DEFAULT 'group';
+-- Both user ids of a direct chat in sorted order, so a pair never gets two of them
+ALTER TABLE chats ADD COLUMN direct_key VARCHAR(73) UNIQUE;
@@ -0,0 +1,8 @@
+ALTER TABLE chats ADD COLUMN name VARCHAR(100);
+ALTER TABLE chats ADD COLUMN topic VARCHAR(1000);
+-- URL of an image hosted elsewhere
+ALTER TABLE chats ADD COLUMN avatar_url VARCHAR(2048);
+-- Nothing new can be posted to an archived chat
+ALTER TABLE chats ADD COLUMN archived BOOLEAN NOT NULL DEFAULT FALSE;
+-- Chats created before this migration get its time
+ALTER TABLE chats ADD COLUMN created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP;
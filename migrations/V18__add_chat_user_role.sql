@@ -0,0 +1,7 @@
+-- One of owner, admin, member or read_only
+ALTER TABLE chat_user ADD COLUMN role VARCHAR(16) NOT NULL DEFAULT 'member';
+
+-- Creators administered their chats so far
+UPDATE chat_user SET role = 'owner'
+FROM chats
+WHERE chats.id = chat_user.chat_id AND chats.created_by = chat_user.user_id;
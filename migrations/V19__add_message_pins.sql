@@ -0,0 +1,4 @@
+ALTER TABLE messages ADD COLUMN pinned_at TIMESTAMP WITH TIME ZONE;
+ALTER TABLE messages ADD COLUMN pinned_by UUID;
+
+CREATE INDEX messages_chat_id_pinned_at_idx ON messages (chat_id, pinned_at) WHERE pinned_at IS NOT NULL;
@@ -0,0 +1,14 @@
+CREATE TABLE chat_invites (
+    code VARCHAR(32) PRIMARY KEY,
+    chat_id UUID NOT NULL,
+    created_by UUID NOT NULL,
+    -- Only this user can accept the invite, anyone with the code otherwise
+    user_id UUID,
+    expires_at TIMESTAMP WITH TIME ZONE,
+    max_uses INTEGER,
+    uses INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX chat_invites_chat_id_idx ON chat_invites (chat_id);
+CREATE INDEX chat_invites_user_id_idx ON chat_invites (user_id) WHERE user_id IS NOT NULL;
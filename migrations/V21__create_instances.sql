@@ -0,0 +1,5 @@
+-- Server instances holding presence rows, one that stops sending heartbeats is gone
+CREATE TABLE instances (
+    instance_id VARCHAR(255) PRIMARY KEY,
+    heartbeat_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
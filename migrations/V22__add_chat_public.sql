@@ -0,0 +1,5 @@
+-- Public chats can be joined by anyone, the others only with an invite
+ALTER TABLE chats ADD COLUMN public BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Group chats used to be open to everyone and older ones may have no owner to invite people
+UPDATE chats SET public = TRUE WHERE kind = 'group';
@@ -0,0 +1,16 @@
+CREATE TABLE sessions (
+    token UUID PRIMARY KEY,
+    user_id UUID NOT NULL,
+    device VARCHAR(255),
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+
+-- Keep the old permanent per-user tokens working as sessions until they expire
+INSERT INTO sessions (token, user_id, expires_at)
+SELECT token, id, CURRENT_TIMESTAMP + INTERVAL '30 days' FROM users;
+
+ALTER TABLE users DROP COLUMN token;
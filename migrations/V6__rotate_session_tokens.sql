@@ -0,0 +1,7 @@
+-- Stable session identifier, the token itself changes on every refresh
+ALTER TABLE sessions ADD COLUMN id UUID NOT NULL DEFAULT gen_random_uuid();
+ALTER TABLE sessions ADD CONSTRAINT sessions_id_key UNIQUE (id);
+
+-- Token replaced by the last rotation, seeing it again means it has leaked
+ALTER TABLE sessions ADD COLUMN previous_token UUID;
+CREATE INDEX sessions_previous_token_idx ON sessions (previous_token);
@@ -0,0 +1,3 @@
+-- Client generated key, retries of the same message are stored only once
+ALTER TABLE messages ADD COLUMN client_msg_id VARCHAR(64);
+ALTER TABLE messages ADD CONSTRAINT messages_client_msg_id_key UNIQUE (chat_id, user_id, client_msg_id);
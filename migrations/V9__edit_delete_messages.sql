@@ -0,0 +1,6 @@
+ALTER TABLE messages ADD COLUMN edited_at TIMESTAMP WITH TIME ZONE;
+-- Deleted messages stay in the history as tombstones without content
+ALTER TABLE messages ADD COLUMN deleted_at TIMESTAMP WITH TIME ZONE;
+
+-- The creator administers the chat, unknown for chats created before
+ALTER TABLE chats ADD COLUMN created_by UUID;
@@ -0,0 +1,815 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    debug_handler,
+    extract::{rejection::JsonRejection, Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    auth::AuthSession,
+    controller::ControllerError,
+    models::{
+        ChatChanges, ChatError, ChatKind, ChatMember, ChatMessage, ChatRole, HistoryPage,
+        ModelChat, ModelChatUser, ModelInvite, ModelMessage,
+    },
+    AppState, Chat, ErrorCode, Invite,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum ApiError {
+    #[error("Chat not found")]
+    ChatNotFound,
+    #[error("Message not found")]
+    MessageNotFound,
+    #[error("Join the chat first")]
+    NotMember,
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+    #[error(transparent)]
+    ControllerError(#[from] ControllerError),
+    #[error(transparent)]
+    InvalidBody(#[from] JsonRejection),
+}
+
+impl From<ChatError> for ApiError {
+    fn from(e: ChatError) -> Self {
+        match e {
+            ChatError::ChatNotFound => Self::ChatNotFound,
+            ChatError::DatabaseError(e) => Self::DatabaseError(e),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::ChatNotFound | Self::MessageNotFound => {
+                (StatusCode::NOT_FOUND, self.to_string()).into_response()
+            }
+            Self::NotMember => (StatusCode::FORBIDDEN, self.to_string()).into_response(),
+            Self::InvalidBody(e) => e.into_response(),
+            Self::DatabaseError(e) => {
+                tracing::error!("{}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            Self::ControllerError(e) => {
+                let status = match e.code() {
+                    ErrorCode::InvalidMessage => StatusCode::BAD_REQUEST,
+                    ErrorCode::ChatNotFound
+                    | ErrorCode::MessageNotFound
+                    | ErrorCode::UserNotFound => StatusCode::NOT_FOUND,
+                    ErrorCode::Forbidden | ErrorCode::NotMember => StatusCode::FORBIDDEN,
+                    ErrorCode::ChatArchived => StatusCode::CONFLICT,
+                    ErrorCode::InviteNotFound => StatusCode::NOT_FOUND,
+                    ErrorCode::InviteExpired => StatusCode::GONE,
+                    _ => {
+                        tracing::error!("{}", e);
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                    }
+                };
+                (status, e.to_string()).into_response()
+            }
+        }
+    }
+}
+
+/* Everything below a chat is open to its members only */
+async fn check_member(state: &AppState, chat_id: Uuid, user_id: Uuid) -> Result<(), ApiError> {
+    ModelChat::get(&state.db, chat_id).await?;
+
+    match ModelChatUser::exists(&state.db, chat_id, user_id).await? {
+        true => Ok(()),
+        false => Err(ApiError::NotMember),
+    }
+}
+
+#[debug_handler]
+pub async fn list_chats(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Result<Json<Vec<Chat>>, ApiError> {
+    let chats = ModelChatUser::get_user_chats(&state.db, auth.user.id)
+        .await?
+        .into_iter()
+        .map(Chat::from_user_chat)
+        .collect();
+
+    Ok(Json(chats))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewChat {
+    kind: Option<ChatKind>,
+}
+
+/* The body is optional, a group chat is created without one but a body has to be valid */
+fn new_chat_kind(body: &[u8]) -> Result<ChatKind, JsonRejection> {
+    if body.is_empty() {
+        return Ok(ChatKind::Group);
+    }
+    let Json(props) = Json::<NewChat>::from_bytes(body)?;
+
+    Ok(props.kind.unwrap_or(ChatKind::Group))
+}
+
+#[debug_handler]
+pub async fn create_chat(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    body: Bytes,
+) -> Result<(StatusCode, Json<Chat>), ApiError> {
+    let kind = new_chat_kind(&body)?;
+    let chat = state.controller.create_chat(auth.user.id, kind).await?;
+
+    Ok((StatusCode::CREATED, Json(chat)))
+}
+
+#[debug_handler]
+pub async fn get_chat(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(chat_id): Path<Uuid>,
+) -> Result<Json<Chat>, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    let chat = ModelChat::get(&state.db, chat_id).await?;
+
+    Ok(Json(Chat::from_model_chat(chat)))
+}
+
+#[debug_handler]
+pub async fn update_chat(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(chat_id): Path<Uuid>,
+    Json(changes): Json<ChatChanges>,
+) -> Result<Json<Chat>, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    let chat = state
+        .controller
+        .update_chat(chat_id, auth.user.id, changes)
+        .await?;
+
+    Ok(Json(chat))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OpenDirect {
+    user_id: Uuid,
+}
+
+#[debug_handler]
+pub async fn open_direct(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Json(props): Json<OpenDirect>,
+) -> Result<Json<Chat>, ApiError> {
+    let chat = state
+        .controller
+        .open_direct(auth.user, props.user_id)
+        .await?;
+
+    Ok(Json(chat))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HistoryQuery {
+    // Id of the oldest message the client already has
+    before: Option<Uuid>,
+    limit: Option<i64>,
+}
+
+#[debug_handler]
+pub async fn list_messages(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(chat_id): Path<Uuid>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<HistoryPage>, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    let before = match query.before {
+        Some(message_id) => Some(
+            ModelMessage::get_cursor(&state.db, chat_id, message_id)
+                .await
+                .map_err(|e| match e {
+                    sqlx::Error::RowNotFound => ApiError::MessageNotFound,
+                    e => e.into(),
+                })?,
+        ),
+        None => None,
+    };
+    let limit = state.config.history_limit(query.limit);
+
+    let page = ModelMessage::get_chat_history(&state.db, chat_id, before, limit).await?;
+
+    Ok(Json(page))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewMessage {
+    content: String,
+    client_msg_id: Option<String>,
+    reply_to: Option<Uuid>,
+}
+
+/* Goes through the controller so connected WebSocket clients get the message live */
+#[debug_handler]
+pub async fn send_message(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(chat_id): Path<Uuid>,
+    Json(props): Json<NewMessage>,
+) -> Result<(StatusCode, Json<ChatMessage>), ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    let message = state
+        .controller
+        .send_message(
+            chat_id,
+            auth.user.id,
+            auth.user.username,
+            props.content,
+            props.client_msg_id,
+            props.reply_to,
+        )
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(message)))
+}
+
+#[debug_handler]
+pub async fn list_replies(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path((chat_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<Vec<ChatMessage>>, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    let replies = state.controller.load_thread(chat_id, message_id).await?;
+
+    Ok(Json(replies))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EditedMessage {
+    content: String,
+}
+
+#[debug_handler]
+pub async fn edit_message(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path((chat_id, message_id)): Path<(Uuid, Uuid)>,
+    Json(props): Json<EditedMessage>,
+) -> Result<StatusCode, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    state
+        .controller
+        .edit_message(chat_id, auth.user.id, message_id, props.content)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler]
+pub async fn delete_message(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path((chat_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    state
+        .controller
+        .delete_message(chat_id, auth.user.id, message_id)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler]
+pub async fn react(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path((chat_id, message_id, emoji)): Path<(Uuid, Uuid, String)>,
+) -> Result<StatusCode, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    state
+        .controller
+        .react(chat_id, auth.user.id, message_id, emoji)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler]
+pub async fn unreact(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path((chat_id, message_id, emoji)): Path<(Uuid, Uuid, String)>,
+) -> Result<StatusCode, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    state
+        .controller
+        .unreact(chat_id, auth.user.id, message_id, emoji)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler]
+pub async fn pin_message(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path((chat_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    state
+        .controller
+        .pin_message(chat_id, auth.user, message_id)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler]
+pub async fn unpin_message(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path((chat_id, message_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    state
+        .controller
+        .unpin_message(chat_id, auth.user.id, message_id)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler]
+pub async fn list_pins(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(chat_id): Path<Uuid>,
+) -> Result<Json<Vec<ChatMessage>>, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    let pins = ModelMessage::get_pinned(&state.db, chat_id).await?;
+
+    Ok(Json(pins))
+}
+
+#[debug_handler]
+pub async fn list_members(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(chat_id): Path<Uuid>,
+) -> Result<Json<Vec<ChatMember>>, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    let members = ModelChatUser::get_members(&state.db, chat_id).await?;
+
+    Ok(Json(members))
+}
+
+#[debug_handler]
+pub async fn remove_member(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path((chat_id, user_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    state
+        .controller
+        .remove_member(chat_id, auth.user.id, user_id)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewRole {
+    role: ChatRole,
+}
+
+#[debug_handler]
+pub async fn set_role(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path((chat_id, user_id)): Path<(Uuid, Uuid)>,
+    Json(props): Json<NewRole>,
+) -> Result<StatusCode, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    state
+        .controller
+        .set_role(chat_id, auth.user, user_id, props.role)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MarkRead {
+    message_id: Uuid,
+}
+
+#[debug_handler]
+pub async fn mark_read(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(chat_id): Path<Uuid>,
+    Json(props): Json<MarkRead>,
+) -> Result<StatusCode, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    state
+        .controller
+        .mark_read(chat_id, auth.user, props.message_id)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler]
+pub async fn join_chat(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(chat_id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    state.controller.join_chat(chat_id, auth.user).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler]
+pub async fn leave_chat(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(chat_id): Path<Uuid>,
+) -> Result<StatusCode, ApiError> {
+    state.controller.leave_chat(chat_id, auth.user).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewInvite {
+    // Invites a specific user, anyone with the code can accept otherwise
+    user_id: Option<Uuid>,
+    expires_at: Option<DateTime<Utc>>,
+    max_uses: Option<i32>,
+}
+
+#[debug_handler]
+pub async fn create_invite(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(chat_id): Path<Uuid>,
+    Json(props): Json<NewInvite>,
+) -> Result<(StatusCode, Json<Invite>), ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    let invite = state
+        .controller
+        .create_invite(
+            chat_id,
+            auth.user.id,
+            props.user_id,
+            props.expires_at,
+            props.max_uses,
+        )
+        .await?;
+
+    Ok((StatusCode::CREATED, Json(invite)))
+}
+
+#[debug_handler]
+pub async fn list_invites(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(chat_id): Path<Uuid>,
+) -> Result<Json<Vec<Invite>>, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    let invites = state.controller.list_invites(chat_id, auth.user.id).await?;
+
+    Ok(Json(invites))
+}
+
+#[debug_handler]
+pub async fn revoke_invite(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path((chat_id, code)): Path<(Uuid, String)>,
+) -> Result<StatusCode, ApiError> {
+    check_member(&state, chat_id, auth.user.id).await?;
+
+    state
+        .controller
+        .revoke_invite(chat_id, auth.user.id, &code)
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/* Pending invites addressed to the current user */
+#[debug_handler]
+pub async fn list_user_invites(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Result<Json<Vec<Invite>>, ApiError> {
+    let invites = ModelInvite::get_pending_for_user(&state.db, auth.user.id, Utc::now())
+        .await?
+        .into_iter()
+        .map(Invite::from_model_invite)
+        .collect();
+
+    Ok(Json(invites))
+}
+
+#[debug_handler]
+pub async fn accept_invite(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+    Path(code): Path<String>,
+) -> Result<Json<Chat>, ApiError> {
+    let chat = state.controller.accept_invite(&code, auth.user).await?;
+
+    Ok(Json(chat))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header::AUTHORIZATION, Method, Request},
+        Router,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        auth,
+        config::TokenMode,
+        test_util::{create_user, test_state, TestDb},
+    };
+
+    struct TestApp {
+        router: Router,
+        state: Arc<AppState>,
+        _db: TestDb,
+    }
+
+    async fn test_app() -> TestApp {
+        let (state, db) = test_state(TokenMode::Session).await;
+
+        TestApp {
+            router: crate::router(state.clone()),
+            state,
+            _db: db,
+        }
+    }
+
+    impl TestApp {
+        async fn token(&self, username: &str) -> String {
+            let user = create_user(&self.state.db, username).await;
+            auth::issue(&self.state, user, None).await.unwrap().token
+        }
+
+        /* Status and JSON body, Null for empty bodies */
+        async fn call(
+            &self,
+            method: Method,
+            uri: &str,
+            token: Option<&str>,
+            body: Option<Value>,
+        ) -> (StatusCode, Value) {
+            let mut request = Request::builder().method(method).uri(uri);
+            if let Some(token) = token {
+                request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+            }
+            let request = match body {
+                Some(body) => request
+                    .header("content-type", "application/json")
+                    .body(Body::from(body.to_string())),
+                None => request.body(Body::empty()),
+            }
+            .unwrap();
+
+            let response = self.router.clone().oneshot(request).await.unwrap();
+            let status = response.status();
+            let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+
+            (status, body)
+        }
+    }
+
+    #[test]
+    fn new_chat_kind_defaults_to_group() {
+        assert!(matches!(new_chat_kind(b""), Ok(ChatKind::Group)));
+        assert!(matches!(new_chat_kind(b"{}"), Ok(ChatKind::Group)));
+        assert!(matches!(
+            new_chat_kind(br#"{"kind": "announcement"}"#),
+            Ok(ChatKind::Announcement)
+        ));
+    }
+
+    #[test]
+    fn new_chat_kind_rejects_invalid_bodies() {
+        for body in [&br#"{"kind": "announcment"}"#[..], b"{", b"[]"] {
+            let status = new_chat_kind(body).unwrap_err().status();
+            assert!(status.is_client_error(), "{}", status);
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_endpoints_need_a_token_and_membership() {
+        let app = test_app().await;
+        let alice = app.token("alice").await;
+        let bob = app.token("bob").await;
+        let (_, chat) = app.call(Method::POST, "/chats", Some(&alice), None).await;
+        let messages = format!("/chats/{}/messages", chat["id"].as_str().unwrap());
+
+        let (anonymous, _) = app.call(Method::GET, &messages, None, None).await;
+        let (invalid, _) = app
+            .call(Method::GET, &messages, Some("not-a-token"), None)
+            .await;
+        let (outsider, _) = app.call(Method::GET, &messages, Some(&bob), None).await;
+        let (unknown, _) = app
+            .call(
+                Method::GET,
+                &format!("/chats/{}/messages", Uuid::new_v4()),
+                Some(&bob),
+                None,
+            )
+            .await;
+
+        assert_eq!(anonymous, StatusCode::UNAUTHORIZED);
+        assert_eq!(invalid, StatusCode::UNAUTHORIZED);
+        assert_eq!(outsider, StatusCode::FORBIDDEN);
+        assert_eq!(unknown, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn members_post_react_and_read_over_rest() {
+        let app = test_app().await;
+        let alice = app.token("alice").await;
+        let bob = app.token("bob").await;
+
+        let (status, chat) = app
+            .call(
+                Method::POST,
+                "/chats",
+                Some(&alice),
+                Some(json!({"kind": "group"})),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let chat_id = chat["id"].as_str().unwrap();
+        app.call(
+            Method::PATCH,
+            &format!("/chats/{}", chat_id),
+            Some(&alice),
+            Some(json!({"public": true})),
+        )
+        .await;
+        let (status, _) = app
+            .call(
+                Method::POST,
+                &format!("/chats/{}/join", chat_id),
+                Some(&bob),
+                None,
+            )
+            .await;
+        assert!(status.is_success(), "{}", status);
+
+        let (status, message) = app
+            .call(
+                Method::POST,
+                &format!("/chats/{}/messages", chat_id),
+                Some(&bob),
+                Some(json!({"content": "hi"})),
+            )
+            .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(message["content"], "hi");
+        let message_id = message["id"].as_str().unwrap();
+
+        // The emoji travels percent-encoded in the path
+        let (status, _) = app
+            .call(
+                Method::PUT,
+                &format!(
+                    "/chats/{}/messages/{}/reactions/%F0%9F%91%8D",
+                    chat_id, message_id
+                ),
+                Some(&alice),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, history) = app
+            .call(
+                Method::GET,
+                &format!("/chats/{}/messages", chat_id),
+                Some(&alice),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let messages = history["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["id"], message_id);
+
+        let (status, members) = app
+            .call(
+                Method::GET,
+                &format!("/chats/{}/members", chat_id),
+                Some(&bob),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(members.as_array().unwrap().len(), 2);
+
+        let (status, chats) = app.call(Method::GET, "/chats", Some(&alice), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(chats[0]["id"], chat_id);
+        assert_eq!(chats[0]["unread_count"], 1);
+    }
+
+    #[tokio::test]
+    async fn only_admins_update_chats_over_rest() {
+        let app = test_app().await;
+        let alice = app.token("alice").await;
+        let bob = app.token("bob").await;
+        let (_, chat) = app.call(Method::POST, "/chats", Some(&alice), None).await;
+        let chat_uri = format!("/chats/{}", chat["id"].as_str().unwrap());
+        app.call(
+            Method::PATCH,
+            &chat_uri,
+            Some(&alice),
+            Some(json!({"public": true})),
+        )
+        .await;
+        let (joined, _) = app
+            .call(
+                Method::POST,
+                &format!("{}/join", chat_uri),
+                Some(&bob),
+                None,
+            )
+            .await;
+        assert!(joined.is_success(), "{}", joined);
+
+        let (member, _) = app
+            .call(
+                Method::PATCH,
+                &chat_uri,
+                Some(&bob),
+                Some(json!({"topic": "Birds"})),
+            )
+            .await;
+        let (status, updated) = app
+            .call(
+                Method::PATCH,
+                &chat_uri,
+                Some(&alice),
+                Some(json!({"topic": "Birds"})),
+            )
+            .await;
+        let (invalid, _) = app
+            .call(
+                Method::PATCH,
+                &chat_uri,
+                Some(&alice),
+                Some(json!({"archived": "yes"})),
+            )
+            .await;
+
+        assert_eq!(member, StatusCode::FORBIDDEN);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(updated["topic"], "Birds");
+        assert!(invalid.is_client_error(), "{}", invalid);
+    }
+}
@@ -4,6 +4,7 @@ use axum::{
 };
 
 #[derive(Debug)]
+#[allow(dead_code)]
 pub struct AppError(anyhow::Error);
 
 impl IntoResponse for AppError {
@@ -0,0 +1,324 @@
+use std::sync::Arc;
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, encode, errors::ErrorKind, Header, Validation};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    config::{JwtKeys, TokenMode},
+    models::{ModelSession, ModelUser},
+    AppState, AuthorisedUser, ErrorCode, User,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    #[error("Missing token")]
+    MissingToken,
+    #[error("Invalid token")]
+    InvalidToken,
+    #[error("Token has expired")]
+    TokenExpired,
+    #[error("Token has been revoked")]
+    TokenRevoked,
+    #[error("Refresh tokens are not issued in session token mode")]
+    RefreshDisabled,
+    #[error(transparent)]
+    DatabaseError(sqlx::Error),
+    #[error(transparent)]
+    JwtError(jsonwebtoken::errors::Error),
+}
+
+impl From<sqlx::Error> for AuthError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => Self::InvalidToken,
+            e => Self::DatabaseError(e),
+        }
+    }
+}
+
+impl AuthError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::MissingToken | Self::InvalidToken | Self::RefreshDisabled => {
+                ErrorCode::InvalidToken
+            }
+            Self::TokenExpired => ErrorCode::TokenExpired,
+            Self::TokenRevoked => ErrorCode::TokenRevoked,
+            Self::DatabaseError(_) | Self::JwtError(_) => ErrorCode::ServerError,
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::RefreshDisabled => (StatusCode::NOT_FOUND, self.to_string()).into_response(),
+            Self::DatabaseError(e) => {
+                tracing::error!("{}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            Self::JwtError(e) => {
+                tracing::error!("{}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            _ => (StatusCode::UNAUTHORIZED, self.to_string()).into_response(),
+        }
+    }
+}
+
+/* Who a valid access token belongs to */
+pub struct Identity {
+    pub user: User,
+    pub session_id: Uuid,
+}
+
+/* JWT access token payload */
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: Uuid,
+    username: String,
+    sid: Uuid,
+    iat: i64,
+    exp: i64,
+}
+
+/* Checks an access token, in jwt mode without touching the database */
+pub async fn authenticate(state: &AppState, token: &str) -> Result<Identity, AuthError> {
+    match &state.config.token_mode {
+        TokenMode::Session => {
+            let token = Uuid::parse_str(token).map_err(|_| AuthError::InvalidToken)?;
+            let session = active_session(state, token).await?;
+            let user = ModelUser::get_by_id(&state.db, session.user_id).await?;
+
+            Ok(Identity {
+                user: User::from_model_user(user),
+                session_id: session.id,
+            })
+        }
+        TokenMode::Jwt(keys) => {
+            let claims = decode::<Claims>(token, &keys.decoding, &Validation::new(keys.algorithm))
+                .map_err(|e| match e.kind() {
+                    ErrorKind::ExpiredSignature => AuthError::TokenExpired,
+                    _ => AuthError::InvalidToken,
+                })?
+                .claims;
+
+            Ok(Identity {
+                user: User {
+                    id: claims.sub,
+                    username: claims.username,
+                },
+                session_id: claims.sid,
+            })
+        }
+    }
+}
+
+/* Starts a new session for a user who just proved their identity */
+pub async fn issue(
+    state: &AppState,
+    user: ModelUser,
+    device: Option<String>,
+) -> Result<AuthorisedUser, AuthError> {
+    let session =
+        ModelSession::create(&state.db, user.id, device, state.config.session_ttl).await?;
+
+    authorise(state, user, session)
+}
+
+/* Exchanges a refresh token for a new access token and a new refresh token */
+pub async fn refresh(state: &AppState, refresh_token: Uuid) -> Result<AuthorisedUser, AuthError> {
+    let TokenMode::Jwt(_) = state.config.token_mode else {
+        return Err(AuthError::RefreshDisabled);
+    };
+
+    // A token that was already rotated is being replayed, so kill the whole session
+    match ModelSession::get_by_previous_token(&state.db, refresh_token).await {
+        Ok(session) => {
+            tracing::warn!("refresh token reused for session {}", session.id);
+            ModelSession::revoke(&state.db, session.id).await?;
+            return Err(AuthError::TokenRevoked);
+        }
+        Err(sqlx::Error::RowNotFound) => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    active_session(state, refresh_token).await?;
+    let session = ModelSession::rotate(&state.db, refresh_token, state.config.session_ttl).await?;
+    let user = ModelUser::get_by_id(&state.db, session.user_id).await?;
+
+    authorise(state, user, session)
+}
+
+async fn active_session(state: &AppState, token: Uuid) -> Result<ModelSession, AuthError> {
+    let session = ModelSession::get_by_token(&state.db, token).await?;
+
+    if session.revoked_at.is_some() {
+        return Err(AuthError::TokenRevoked);
+    }
+    if session.is_expired() {
+        return Err(AuthError::TokenExpired);
+    }
+
+    Ok(session)
+}
+
+fn authorise(
+    state: &AppState,
+    user: ModelUser,
+    session: ModelSession,
+) -> Result<AuthorisedUser, AuthError> {
+    match &state.config.token_mode {
+        TokenMode::Session => Ok(AuthorisedUser {
+            id: user.id,
+            username: user.username,
+            token: session.token.to_string(),
+            expires_at: session.expires_at,
+            refresh_token: None,
+        }),
+        TokenMode::Jwt(keys) => {
+            let (token, expires_at) = sign(keys, &user, &session)?;
+            Ok(AuthorisedUser {
+                id: user.id,
+                username: user.username,
+                token,
+                expires_at,
+                refresh_token: Some(session.token),
+            })
+        }
+    }
+}
+
+fn sign(
+    keys: &JwtKeys,
+    user: &ModelUser,
+    session: &ModelSession,
+) -> Result<(String, DateTime<Utc>), AuthError> {
+    let issued_at = Utc::now();
+    let expires_at = issued_at + keys.access_ttl;
+    let claims = Claims {
+        sub: user.id,
+        username: user.username.clone(),
+        sid: session.id,
+        iat: issued_at.timestamp(),
+        exp: expires_at.timestamp(),
+    };
+
+    let token = encode(&Header::new(keys.algorithm), &claims, &keys.encoding)
+        .map_err(AuthError::JwtError)?;
+
+    Ok((token, expires_at))
+}
+
+/* Authorised user of an HTTP request, taken from `Authorization: Bearer <token>` */
+pub struct AuthSession {
+    pub user: User,
+    pub session_id: Uuid,
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for AuthSession {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let header = parts
+            .headers
+            .get(AUTHORIZATION)
+            .ok_or(AuthError::MissingToken)?;
+        let token = header
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(AuthError::InvalidToken)?;
+
+        let identity = authenticate(state, token.trim()).await?;
+
+        Ok(Self {
+            user: identity.user,
+            session_id: identity.session_id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{create_user, jwt_mode, test_state};
+
+    #[tokio::test]
+    async fn refresh_rotates_the_refresh_token() {
+        let (state, _db) = test_state(jwt_mode()).await;
+        let user = create_user(&state.db, "alice").await;
+        let issued = issue(&state, user, None).await.unwrap();
+        let first = issued.refresh_token.unwrap();
+
+        let refreshed = refresh(&state, first).await.unwrap();
+        let second = refreshed.refresh_token.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(refreshed.id, issued.id);
+
+        // The rotated token keeps working
+        let third = refresh(&state, second)
+            .await
+            .unwrap()
+            .refresh_token
+            .unwrap();
+        assert_ne!(second, third);
+    }
+
+    #[tokio::test]
+    async fn reusing_a_rotated_token_revokes_the_session() {
+        let (state, _db) = test_state(jwt_mode()).await;
+        let user = create_user(&state.db, "alice").await;
+        let first = issue(&state, user, None)
+            .await
+            .unwrap()
+            .refresh_token
+            .unwrap();
+        let second = refresh(&state, first).await.unwrap().refresh_token.unwrap();
+
+        assert!(matches!(
+            refresh(&state, first).await,
+            Err(AuthError::TokenRevoked)
+        ));
+        // The legitimate holder is logged out as well
+        assert!(matches!(
+            refresh(&state, second).await,
+            Err(AuthError::TokenRevoked)
+        ));
+    }
+
+    #[tokio::test]
+    async fn refresh_rejects_unknown_tokens() {
+        let (state, _db) = test_state(jwt_mode()).await;
+
+        assert!(matches!(
+            refresh(&state, Uuid::new_v4()).await,
+            Err(AuthError::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn refresh_is_disabled_in_session_mode() {
+        let (state, _db) = test_state(TokenMode::Session).await;
+        let user = create_user(&state.db, "alice").await;
+        let issued = issue(&state, user, None).await.unwrap();
+        assert!(issued.refresh_token.is_none());
+
+        assert!(matches!(
+            refresh(&state, Uuid::new_v4()).await,
+            Err(AuthError::RefreshDisabled)
+        ));
+    }
+}
@@ -0,0 +1,434 @@
+use std::{collections::HashMap, mem, sync::Arc, sync::Mutex, time::Duration};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgListener, PgPool};
+use tokio::sync::{
+    broadcast::{self, Receiver, Sender},
+    mpsc::{
+        self,
+        error::{TryRecvError, TrySendError},
+    },
+};
+use uuid::Uuid;
+
+use crate::{models::ModelBusEvent, ResponseMessage};
+
+const NOTIFY_CHANNEL: &str = "chat_events";
+// Postgres rejects NOTIFY payloads of 8000 bytes and more
+const MAX_NOTIFY_PAYLOAD: usize = 7900;
+// Listeners load stored events right after the notification, older ones are garbage
+const STORED_EVENT_TTL: chrono::Duration = chrono::Duration::minutes(5);
+const LISTEN_RETRY_DELAY: Duration = Duration::from_secs(1);
+// Tasks notifying in parallel, the events of a chat always go through the same one to stay in order
+const NOTIFY_WORKERS: usize = 4;
+// Events a notify task buffers, the ones that don't fit are dropped and the chat is resynced instead
+const NOTIFY_QUEUE_SIZE: usize = 256;
+
+/* Fans chat events out to the connections of every server instance */
+pub trait Bus: Send + Sync {
+    /* Delivers to this instance too, never blocks */
+    fn publish(&self, chat_id: Uuid, message: ResponseMessage);
+}
+
+/* Broadcast channels of the chats this instance has connections to */
+pub struct Channels {
+    // Created when the first connection enters a chat and dropped when the last one leaves
+    senders: Mutex<HashMap<Uuid, Sender<ResponseMessage>>>,
+    capacity: usize,
+}
+
+impl Channels {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            senders: Mutex::new(HashMap::new()),
+            capacity,
+        }
+    }
+
+    pub fn subscribe(&self, chat_id: Uuid) -> Receiver<ResponseMessage> {
+        self.senders
+            .lock()
+            .unwrap()
+            .entry(chat_id)
+            .or_insert_with(|| broadcast::channel(self.capacity).0)
+            .subscribe()
+    }
+
+    /* Drops the chat channel once nobody listens to it anymore */
+    pub fn release(&self, chat_id: Uuid) {
+        let mut senders = self.senders.lock().unwrap();
+        if senders
+            .get(&chat_id)
+            .is_some_and(|sender| sender.receiver_count() == 0)
+        {
+            senders.remove(&chat_id);
+        }
+    }
+
+    fn deliver(&self, chat_id: Uuid, message: ResponseMessage) {
+        if let Some(sender) = self.senders.lock().unwrap().get(&chat_id) {
+            // Only fails when there are no receivers, which is fine
+            let _ = sender.send(message);
+        }
+    }
+
+    /* For when events may have been lost without knowing how many or of which chats */
+    fn resync_all(&self) {
+        for (chat_id, sender) in self.senders.lock().unwrap().iter() {
+            let _ = sender.send(ResponseMessage::Resync {
+                chat_id: *chat_id,
+                missed: 0,
+            });
+        }
+    }
+}
+
+/* Single instance, events never leave the process */
+pub struct MemoryBus {
+    channels: Arc<Channels>,
+}
+
+impl MemoryBus {
+    pub fn new(channels: Arc<Channels>) -> Self {
+        Self { channels }
+    }
+}
+
+impl Bus for MemoryBus {
+    fn publish(&self, chat_id: Uuid, message: ResponseMessage) {
+        self.channels.deliver(chat_id, message);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Event {
+    // Instance that published the event and already delivered it to its connections
+    origin: String,
+    chat_id: Uuid,
+    message: ResponseMessage,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Notification {
+    Inline(Box<Event>),
+    // Row in bus_events, the origin lets instances skip their own events without loading them
+    Stored { origin: String, id: i64 },
+}
+
+/* Any number of instances sharing a database, events go through LISTEN/NOTIFY */
+pub struct PgBus {
+    instance_id: String,
+    channels: Arc<Channels>,
+    workers: Vec<NotifyWorker>,
+}
+
+struct NotifyWorker {
+    queue: mpsc::Sender<Event>,
+    // Number of events dropped per chat since the queue last ran empty
+    dropped: Arc<Mutex<HashMap<Uuid, u64>>>,
+}
+
+impl PgBus {
+    /* Spawns the tasks notifying and listening, has to run inside the runtime */
+    pub fn start(db: PgPool, channels: Arc<Channels>, instance_id: String) -> Self {
+        let workers = (0..NOTIFY_WORKERS)
+            .map(|_| {
+                let (queue, events) = mpsc::channel(NOTIFY_QUEUE_SIZE);
+                let dropped = Arc::new(Mutex::new(HashMap::new()));
+                tokio::spawn(notify(
+                    db.clone(),
+                    events,
+                    dropped.clone(),
+                    instance_id.clone(),
+                ));
+                NotifyWorker { queue, dropped }
+            })
+            .collect();
+
+        tokio::spawn(listen(db, channels.clone(), instance_id.clone()));
+
+        Self {
+            instance_id,
+            channels,
+            workers,
+        }
+    }
+
+    fn worker(&self, chat_id: Uuid) -> &NotifyWorker {
+        &self.workers[(chat_id.as_u128() % self.workers.len() as u128) as usize]
+    }
+}
+
+impl Bus for PgBus {
+    fn publish(&self, chat_id: Uuid, message: ResponseMessage) {
+        // Local connections don't wait for the database, the listener skips the event later
+        self.channels.deliver(chat_id, message.clone());
+
+        let worker = self.worker(chat_id);
+        let event = Event {
+            origin: self.instance_id.clone(),
+            chat_id,
+            message,
+        };
+        match worker.queue.try_send(event) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!("Notify queue is full, dropped an event of chat {}", chat_id);
+                *worker.dropped.lock().unwrap().entry(chat_id).or_insert(0) += 1;
+            }
+            // Only once the runtime shuts down
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+async fn notify(
+    db: PgPool,
+    mut events: mpsc::Receiver<Event>,
+    dropped: Arc<Mutex<HashMap<Uuid, u64>>>,
+    instance_id: String,
+) {
+    loop {
+        let event = match events.try_recv() {
+            Ok(event) => event,
+            Err(TryRecvError::Empty) => {
+                // Caught up, so the other instances resync after every event that was dropped
+                let resyncs = mem::take(&mut *dropped.lock().unwrap());
+                for (chat_id, missed) in resyncs {
+                    let event = Event {
+                        origin: instance_id.clone(),
+                        chat_id,
+                        message: ResponseMessage::Resync { chat_id, missed },
+                    };
+                    if let Err(e) = notify_event(&db, event).await {
+                        tracing::error!("Failed to publish resync: {}", e);
+                    }
+                }
+                match events.recv().await {
+                    Some(event) => event,
+                    None => return,
+                }
+            }
+            Err(TryRecvError::Disconnected) => return,
+        };
+        if let Err(e) = notify_event(&db, event).await {
+            tracing::error!("Failed to publish event: {}", e);
+        }
+    }
+}
+
+async fn notify_event(db: &PgPool, event: Event) -> anyhow::Result<()> {
+    let payload = encode(db, event).await?;
+    ModelBusEvent::notify(db, NOTIFY_CHANNEL, &payload).await?;
+
+    Ok(())
+}
+
+/* Stores events too large for a notification and sends a reference instead */
+async fn encode(db: &PgPool, event: Event) -> anyhow::Result<String> {
+    let origin = event.origin.clone();
+    let payload = serde_json::to_string(&Notification::Inline(Box::new(event)))?;
+
+    if payload.len() <= MAX_NOTIFY_PAYLOAD {
+        return Ok(payload);
+    }
+
+    ModelBusEvent::delete_older_than(db, Utc::now() - STORED_EVENT_TTL).await?;
+    let id = ModelBusEvent::create(db, &payload).await?;
+
+    Ok(serde_json::to_string(&Notification::Stored { origin, id })?)
+}
+
+async fn listen(db: PgPool, channels: Arc<Channels>, instance_id: String) {
+    loop {
+        if let Err(e) = listen_events(&db, &channels, &instance_id).await {
+            tracing::error!("Listening for events failed: {}", e);
+        }
+        tokio::time::sleep(LISTEN_RETRY_DELAY).await;
+    }
+}
+
+async fn listen_events(db: &PgPool, channels: &Channels, instance_id: &str) -> anyhow::Result<()> {
+    let mut listener = PgListener::connect_with(db).await?;
+    listener.listen(NOTIFY_CHANNEL).await?;
+
+    // Events published while no listener was connected are lost
+    channels.resync_all();
+
+    loop {
+        // None when the connection was lost, reconnecting here would hide the lost events
+        let Some(notification) = listener.try_recv().await? else {
+            anyhow::bail!("Lost the connection");
+        };
+        match decode(db, instance_id, notification.payload()).await {
+            Ok(Some(event)) => channels.deliver(event.chat_id, event.message),
+            Ok(None) => {}
+            Err(e) => tracing::error!("Dropped event: {}", e),
+        }
+    }
+}
+
+/* Skips the events of this instance, which delivered them when publishing */
+async fn decode(db: &PgPool, instance_id: &str, payload: &str) -> anyhow::Result<Option<Event>> {
+    let event = match serde_json::from_str(payload)? {
+        Notification::Inline(event) => *event,
+        Notification::Stored { origin, .. } if origin == instance_id => return Ok(None),
+        Notification::Stored { id, .. } => {
+            let payload = ModelBusEvent::get(db, id).await?;
+            match serde_json::from_str(&payload)? {
+                Notification::Inline(event) => *event,
+                Notification::Stored { .. } => {
+                    anyhow::bail!("Stored event {} is a reference", id)
+                }
+            }
+        }
+    };
+
+    Ok((event.origin != instance_id).then_some(event))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{sync::broadcast::error::TryRecvError, time::timeout};
+
+    use super::*;
+    use crate::{test_util::test_db, ErrorCode};
+
+    fn json(message: &ResponseMessage) -> serde_json::Value {
+        serde_json::to_value(message).unwrap()
+    }
+
+    fn resync(chat_id: Uuid) -> ResponseMessage {
+        ResponseMessage::Resync { chat_id, missed: 1 }
+    }
+
+    fn event(origin: &str, message_len: usize) -> Event {
+        Event {
+            origin: origin.to_string(),
+            chat_id: Uuid::new_v4(),
+            message: ResponseMessage::Error {
+                code: ErrorCode::ServerError,
+                message: "x".repeat(message_len),
+                request_id: None,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn memory_bus_delivers_to_the_chat_only() {
+        let channels = Arc::new(Channels::new(10));
+        let bus = MemoryBus::new(channels.clone());
+        let (chat, other_chat) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut receiver = channels.subscribe(chat);
+        let mut other_receiver = channels.subscribe(other_chat);
+
+        bus.publish(chat, resync(chat));
+        // Chats without connections have no channel
+        bus.publish(Uuid::new_v4(), resync(chat));
+
+        assert_eq!(json(&receiver.try_recv().unwrap()), json(&resync(chat)));
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::Empty)));
+        assert!(matches!(
+            other_receiver.try_recv(),
+            Err(TryRecvError::Empty)
+        ));
+    }
+
+    #[tokio::test]
+    async fn channels_are_released_with_their_last_receiver() {
+        let channels = Channels::new(10);
+        let chat = Uuid::new_v4();
+        let first = channels.subscribe(chat);
+        let mut second = channels.subscribe(chat);
+
+        drop(first);
+        channels.release(chat);
+        channels.deliver(chat, resync(chat));
+        assert_eq!(json(&second.try_recv().unwrap()), json(&resync(chat)));
+
+        drop(second);
+        channels.release(chat);
+        assert!(channels.senders.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resync_all_reaches_every_chat() {
+        let channels = Channels::new(10);
+        let chats = [Uuid::new_v4(), Uuid::new_v4()];
+        let mut receivers = chats.map(|chat| channels.subscribe(chat));
+
+        channels.resync_all();
+
+        for (chat, receiver) in chats.iter().zip(receivers.iter_mut()) {
+            let expected = ResponseMessage::Resync {
+                chat_id: *chat,
+                missed: 0,
+            };
+            assert_eq!(json(&receiver.try_recv().unwrap()), json(&expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn small_events_are_sent_inline() {
+        let db = test_db().await;
+        let sent = event("a", 10);
+        let expected = json(&sent.message);
+
+        let payload = encode(&db, sent).await.unwrap();
+        assert!(matches!(
+            serde_json::from_str(&payload).unwrap(),
+            Notification::Inline(_)
+        ));
+
+        let received = decode(&db, "b", &payload).await.unwrap().unwrap();
+        assert_eq!(received.origin, "a");
+        assert_eq!(json(&received.message), expected);
+        assert!(decode(&db, "a", &payload).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn large_events_are_stored() {
+        let db = test_db().await;
+        let sent = event("a", MAX_NOTIFY_PAYLOAD);
+        let (chat_id, expected) = (sent.chat_id, json(&sent.message));
+
+        let payload = encode(&db, sent).await.unwrap();
+        assert!(payload.len() <= MAX_NOTIFY_PAYLOAD);
+        assert!(matches!(
+            serde_json::from_str(&payload).unwrap(),
+            Notification::Stored { .. }
+        ));
+
+        let received = decode(&db, "b", &payload).await.unwrap().unwrap();
+        assert_eq!(received.chat_id, chat_id);
+        assert_eq!(json(&received.message), expected);
+        assert!(decode(&db, "a", &payload).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn pg_bus_delivers_locally_once_and_to_other_instances() {
+        let db = test_db().await;
+        let (channels_a, channels_b) = (Arc::new(Channels::new(10)), Arc::new(Channels::new(10)));
+        let bus_a = PgBus::start(db.clone(), channels_a.clone(), "a".to_string());
+        let _bus_b = PgBus::start(db.clone(), channels_b.clone(), "b".to_string());
+        let chat = Uuid::new_v4();
+        let mut receiver_a = channels_a.subscribe(chat);
+        let mut receiver_b = channels_b.subscribe(chat);
+
+        // Give the listeners time to connect, they resync on connecting
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        while receiver_a.try_recv().is_ok() {}
+        while receiver_b.try_recv().is_ok() {}
+
+        bus_a.publish(chat, resync(chat));
+
+        assert_eq!(json(&receiver_a.try_recv().unwrap()), json(&resync(chat)));
+        let received = timeout(Duration::from_secs(5), receiver_b.recv()).await;
+        assert_eq!(json(&received.unwrap().unwrap()), json(&resync(chat)));
+        // The listener of the publishing instance skips the event
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(matches!(receiver_a.try_recv(), Err(TryRecvError::Empty)));
+    }
+}
@@ -0,0 +1,165 @@
+use std::{env, fs, str::FromStr, time};
+
+use chrono::Duration;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use uuid::Uuid;
+
+// Upper bound for the number of messages a client can load at once
+const MAX_HISTORY_PAGE_SIZE: i64 = 200;
+
+/* How access tokens are issued and checked */
+pub enum TokenMode {
+    // Opaque session tokens looked up in the sessions table
+    Session,
+    // Signed JWT access tokens plus opaque refresh tokens
+    Jwt(JwtKeys),
+}
+
+pub struct JwtKeys {
+    pub algorithm: Algorithm,
+    pub encoding: EncodingKey,
+    pub decoding: DecodingKey,
+    pub access_ttl: Duration,
+}
+
+/* How chat events reach the connections */
+pub enum BusKind {
+    // In process, for a single server
+    Memory,
+    // Postgres LISTEN/NOTIFY, for several servers sharing a database
+    Postgres,
+}
+
+pub struct Config {
+    // Several servers on one host need different ports
+    pub port: u16,
+    pub token_mode: TokenMode,
+    // Lifetime of a session, which is the refresh token lifetime in jwt mode
+    pub session_ttl: Duration,
+    // How long a client may take to send Join when the upgrade was not authorised
+    pub handshake_timeout: time::Duration,
+    // Number of messages sent on entering a chat and the default LoadHistory limit
+    pub history_page_size: i64,
+    // Owner of the presence rows of this server, has to be unique per running server
+    pub instance_id: String,
+    // Events a chat buffers for its slowest connection before that one has to resync
+    pub chat_channel_capacity: usize,
+    // Messages waiting to be written to a single client socket
+    pub outbound_queue_size: usize,
+    pub bus: BusKind,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let token_mode = match env::var("TOKEN_MODE").as_deref() {
+            Ok("jwt") => TokenMode::Jwt(JwtKeys::from_env()),
+            Ok("session") | Err(_) => TokenMode::Session,
+            Ok(mode) => panic!("TOKEN_MODE must be either session or jwt, got {}", mode),
+        };
+
+        let bus = match env::var("BUS").as_deref() {
+            Ok("memory") | Err(_) => BusKind::Memory,
+            Ok("postgres") => BusKind::Postgres,
+            Ok(bus) => panic!("BUS must be either memory or postgres, got {}", bus),
+        };
+
+        Self {
+            port: parse_var("PORT", 3001),
+            token_mode,
+            session_ttl: Duration::days(parse_var("SESSION_TTL_DAYS", 30)),
+            handshake_timeout: time::Duration::from_secs(parse_var(
+                "HANDSHAKE_TIMEOUT_SECONDS",
+                10,
+            )),
+            history_page_size: parse_var("HISTORY_PAGE_SIZE", 50),
+            // A fresh id per run, the connections of a previous run are reclaimed once it times out
+            instance_id: env::var("INSTANCE_ID").unwrap_or_else(|_| Uuid::new_v4().to_string()),
+            chat_channel_capacity: parse_capacity("CHAT_CHANNEL_CAPACITY", 100),
+            // Sized for bursts of a busy chat, a client whose queue is full gets disconnected
+            outbound_queue_size: parse_capacity("OUTBOUND_QUEUE_SIZE", 128),
+            bus,
+        }
+    }
+}
+
+impl Config {
+    pub fn history_limit(&self, requested: Option<i64>) -> i64 {
+        requested
+            .unwrap_or(self.history_page_size)
+            .clamp(1, MAX_HISTORY_PAGE_SIZE)
+    }
+}
+
+impl JwtKeys {
+    fn from_env() -> Self {
+        let access_ttl = Duration::minutes(parse_var("JWT_ACCESS_TTL_MINUTES", 15));
+
+        match env::var("JWT_ALGORITHM").as_deref() {
+            Ok("HS256") | Err(_) => {
+                let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+                Self {
+                    algorithm: Algorithm::HS256,
+                    encoding: EncodingKey::from_secret(secret.as_bytes()),
+                    decoding: DecodingKey::from_secret(secret.as_bytes()),
+                    access_ttl,
+                }
+            }
+            Ok("EdDSA") => {
+                let private_key = read_var_file("JWT_PRIVATE_KEY_FILE");
+                let public_key = read_var_file("JWT_PUBLIC_KEY_FILE");
+                Self {
+                    algorithm: Algorithm::EdDSA,
+                    encoding: EncodingKey::from_ed_pem(&private_key)
+                        .expect("JWT_PRIVATE_KEY_FILE must be an Ed25519 PEM private key"),
+                    decoding: DecodingKey::from_ed_pem(&public_key)
+                        .expect("JWT_PUBLIC_KEY_FILE must be an Ed25519 PEM public key"),
+                    access_ttl,
+                }
+            }
+            Ok(algorithm) => panic!(
+                "JWT_ALGORITHM must be either HS256 or EdDSA, got {}",
+                algorithm
+            ),
+        }
+    }
+}
+
+fn parse_var<T: FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} has an invalid value", name)),
+        Err(_) => default,
+    }
+}
+
+// Channels can't be created with a capacity of 0
+fn parse_capacity(name: &str, default: usize) -> usize {
+    let capacity = parse_var(name, default);
+    if capacity < 1 {
+        panic!("{} must be at least 1, got {}", name, capacity);
+    }
+    capacity
+}
+
+fn read_var_file(name: &str) -> Vec<u8> {
+    let path = env::var(name).unwrap_or_else(|_| panic!("{} must be set", name));
+    fs::read(&path).unwrap_or_else(|e| panic!("Failed to read {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capacity_defaults_when_unset() {
+        assert_eq!(parse_capacity("TEST_CAPACITY_UNSET", 100), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "TEST_CAPACITY_ZERO must be at least 1")]
+    fn capacity_rejects_zero() {
+        env::set_var("TEST_CAPACITY_ZERO", "0");
+        parse_capacity("TEST_CAPACITY_ZERO", 100);
+    }
+}
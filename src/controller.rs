@@ -0,0 +1,2227 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast::Receiver,
+    time::{sleep_until, Instant},
+};
+use uuid::Uuid;
+
+use crate::{
+    bus::{Bus, Channels, MemoryBus, PgBus},
+    config::{BusKind, Config},
+    models::{
+        ChatChanges, ChatError, ChatKind, ChatMessage, ChatRole, InviteAcceptance, Leaving,
+        ModelChat, ModelChatUser, ModelInvite, ModelMessage, ModelPresence, ModelReaction,
+        ModelUser,
+    },
+    Chat, ErrorCode, Invite, ResponseMessage, User,
+};
+
+const MAX_CLIENT_MSG_ID_LENGTH: usize = 64;
+const MAX_EMOJI_LENGTH: usize = 32;
+const MAX_CHAT_NAME_LENGTH: usize = 100;
+const MAX_CHAT_TOPIC_LENGTH: usize = 1000;
+const MAX_AVATAR_URL_LENGTH: usize = 2048;
+// Typing events of a user are relayed at most this often
+const TYPING_THROTTLE: Duration = Duration::from_secs(3);
+// A user who sent no Typing for this long has stopped typing
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+// Instances tell they are alive this often
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+// An instance without a heartbeat for this long is dead and its connections are reclaimed
+const INSTANCE_TIMEOUT: chrono::Duration = chrono::Duration::seconds(30);
+
+pub struct Controller {
+    db: Pool<Postgres>,
+    // Presence rows created by this server are tagged with it
+    instance_id: String,
+    channels: Arc<Channels>,
+    bus: Arc<dyn Bus>,
+    typing: Arc<Mutex<TypingUsers>>,
+}
+
+/* Users currently typing, keyed by chat and user id */
+#[derive(Default)]
+struct TypingUsers {
+    users: HashMap<(Uuid, Uuid), Typing>,
+}
+
+struct Typing {
+    last_relayed: Instant,
+    last_seen: Instant,
+}
+
+#[derive(Debug, PartialEq)]
+enum TypingUpdate {
+    Started,
+    Relayed,
+    Throttled,
+}
+
+#[derive(Debug, PartialEq)]
+enum TypingExpiry {
+    // Stopped by a message or by leaving the chat
+    Stopped,
+    Expired,
+    // Typed again since, to be checked at this time
+    Pending(Instant),
+}
+
+impl TypingUsers {
+    fn typed(&mut self, key: (Uuid, Uuid), now: Instant) -> TypingUpdate {
+        match self.users.get_mut(&key) {
+            Some(state) => {
+                state.last_seen = now;
+                if now.duration_since(state.last_relayed) < TYPING_THROTTLE {
+                    return TypingUpdate::Throttled;
+                }
+                state.last_relayed = now;
+                TypingUpdate::Relayed
+            }
+            None => {
+                self.users.insert(
+                    key,
+                    Typing {
+                        last_relayed: now,
+                        last_seen: now,
+                    },
+                );
+                TypingUpdate::Started
+            }
+        }
+    }
+
+    /* Forgets users who sent no Typing for TYPING_TIMEOUT */
+    fn expire(&mut self, key: (Uuid, Uuid), now: Instant) -> TypingExpiry {
+        let Some(state) = self.users.get(&key) else {
+            return TypingExpiry::Stopped;
+        };
+        let deadline = state.last_seen + TYPING_TIMEOUT;
+        if deadline > now {
+            return TypingExpiry::Pending(deadline);
+        }
+
+        self.users.remove(&key);
+        TypingExpiry::Expired
+    }
+
+    /* Returns false if the user wasn't typing */
+    fn stop(&mut self, key: (Uuid, Uuid)) -> bool {
+        self.users.remove(&key).is_some()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ControllerError {
+    #[error("client_msg_id must be at most 64 characters long")]
+    InvalidClientMsgId,
+    #[error("reply_to must be a message of the same chat that isn't deleted")]
+    InvalidReplyTo,
+    #[error("emoji must be between 1 and 32 bytes long")]
+    InvalidEmoji,
+    #[error("name, topic and avatar_url must be at most 100, 1000 and 2048 characters long")]
+    InvalidChatChanges,
+    #[error("expires_at must be in the future and max_uses at least 1")]
+    InvalidInvite,
+    #[error("Message not found")]
+    MessageNotFound,
+    #[error("Invite not found")]
+    InviteNotFound,
+    #[error("The invite has expired or is used up")]
+    InviteExpired,
+    #[error("Only the author or a chat admin can delete this message")]
+    Forbidden,
+    #[error("Only the author can edit this message")]
+    NotAuthor,
+    #[error("Only a chat admin can do that")]
+    NotChatAdmin,
+    #[error("Only the chat owner can change roles")]
+    NotChatOwner,
+    #[error("Make someone else the owner before leaving")]
+    OwnerLeaving,
+    #[error("The owner can't change their own role")]
+    InvalidRole,
+    #[error("This chat is read-only for you")]
+    ReadOnly,
+    #[error("Join the chat first")]
+    NotMember,
+    #[error("The chat is archived")]
+    ChatArchived,
+    #[error("User not found")]
+    UserNotFound,
+    #[error("A direct chat is opened with another user")]
+    InvalidDirect,
+    #[error("Direct chats can't be joined")]
+    DirectChat,
+    #[error("This chat is joined with an invite")]
+    PrivateChat,
+    #[error(transparent)]
+    ChatError(#[from] ChatError),
+    #[error(transparent)]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl ControllerError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::InvalidClientMsgId
+            | Self::InvalidReplyTo
+            | Self::InvalidEmoji
+            | Self::InvalidDirect
+            | Self::InvalidChatChanges
+            | Self::InvalidRole
+            | Self::InvalidInvite => ErrorCode::InvalidMessage,
+            Self::InviteNotFound => ErrorCode::InviteNotFound,
+            Self::InviteExpired => ErrorCode::InviteExpired,
+            Self::MessageNotFound => ErrorCode::MessageNotFound,
+            Self::UserNotFound => ErrorCode::UserNotFound,
+            Self::Forbidden
+            | Self::NotAuthor
+            | Self::DirectChat
+            | Self::PrivateChat
+            | Self::NotChatAdmin
+            | Self::NotChatOwner
+            | Self::OwnerLeaving
+            | Self::ReadOnly => ErrorCode::Forbidden,
+            Self::NotMember => ErrorCode::NotMember,
+            Self::ChatArchived => ErrorCode::ChatArchived,
+            Self::ChatError(ChatError::ChatNotFound) => ErrorCode::ChatNotFound,
+            Self::ChatError(ChatError::DatabaseError(_)) | Self::DatabaseError(_) => {
+                ErrorCode::ServerError
+            }
+        }
+    }
+}
+
+impl Controller {
+    pub fn new(db: Pool<Postgres>, config: &Config) -> Self {
+        let channels = Arc::new(Channels::new(config.chat_channel_capacity));
+        let bus: Arc<dyn Bus> = match config.bus {
+            BusKind::Memory => Arc::new(MemoryBus::new(channels.clone())),
+            BusKind::Postgres => Arc::new(PgBus::start(
+                db.clone(),
+                channels.clone(),
+                config.instance_id.clone(),
+            )),
+        };
+
+        tokio::spawn(heartbeat(
+            db.clone(),
+            config.instance_id.clone(),
+            bus.clone(),
+        ));
+
+        Self {
+            db,
+            instance_id: config.instance_id.clone(),
+            channels,
+            bus,
+            typing: Arc::new(Mutex::new(TypingUsers::default())),
+        }
+    }
+
+    pub async fn create_chat(
+        &self,
+        user_id: Uuid,
+        kind: ChatKind,
+    ) -> Result<Chat, ControllerError> {
+        if kind == ChatKind::Direct {
+            return Err(ControllerError::InvalidDirect);
+        }
+
+        let chat = ModelChat::new(&self.db, user_id, kind).await?;
+        // The creator is the first member and owner of a new chat
+        ModelChatUser::create(&self.db, chat.id, user_id, ChatRole::Owner).await?;
+
+        Ok(Chat::from_model_chat(chat))
+    }
+
+    /* The same chat is returned for a pair every time, whoever opens it */
+    pub async fn open_direct(&self, user: User, other_id: Uuid) -> Result<Chat, ControllerError> {
+        if user.id == other_id {
+            return Err(ControllerError::InvalidDirect);
+        }
+        ModelUser::get_by_id(&self.db, other_id)
+            .await
+            .map_err(user_error)?;
+
+        let (chat, created) = ModelChat::get_or_create_direct(&self.db, user.id, other_id).await?;
+        if !created && ModelChatUser::create(&self.db, chat.id, user.id, ChatRole::Member).await? {
+            // The caller had left and comes back, the other side stays out if they left too
+            self.broadcast(
+                chat.id,
+                ResponseMessage::MemberJoined {
+                    chat_id: chat.id,
+                    user,
+                },
+            );
+        }
+
+        Ok(Chat::from_model_chat(chat))
+    }
+
+    /* Members see the change live */
+    pub async fn update_chat(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        changes: ChatChanges,
+    ) -> Result<Chat, ControllerError> {
+        let too_long = |field: &Option<String>, max: usize| {
+            field
+                .as_ref()
+                .is_some_and(|value| value.chars().count() > max)
+        };
+        if too_long(&changes.name, MAX_CHAT_NAME_LENGTH)
+            || too_long(&changes.topic, MAX_CHAT_TOPIC_LENGTH)
+            || too_long(&changes.avatar_url, MAX_AVATAR_URL_LENGTH)
+        {
+            return Err(ControllerError::InvalidChatChanges);
+        }
+
+        ModelChat::get(&self.db, chat_id).await?;
+        if !self.member_role(chat_id, user_id).await?.can_moderate() {
+            return Err(ControllerError::NotChatAdmin);
+        }
+
+        let chat = Chat::from_model_chat(ModelChat::update(&self.db, chat_id, &changes).await?);
+        self.broadcast(chat_id, ResponseMessage::ChatUpdated { chat: chat.clone() });
+
+        Ok(chat)
+    }
+
+    /* Makes the user a member of the chat */
+    pub async fn join_chat(&self, chat_id: Uuid, user: User) -> Result<(), ControllerError> {
+        let chat = ModelChat::get(&self.db, chat_id).await?;
+        let role = chat
+            .kind
+            .joining_role()
+            .ok_or(ControllerError::DirectChat)?;
+        if !chat.public && !ModelChatUser::exists(&self.db, chat_id, user.id).await? {
+            return Err(ControllerError::PrivateChat);
+        }
+
+        if ModelChatUser::create(&self.db, chat_id, user.id, role).await? {
+            self.broadcast(chat_id, ResponseMessage::MemberJoined { chat_id, user });
+        }
+
+        Ok(())
+    }
+
+    /* A chat with other members always keeps an owner */
+    pub async fn leave_chat(&self, chat_id: Uuid, user: User) -> Result<(), ControllerError> {
+        match ModelChatUser::leave(&self.db, chat_id, user.id).await? {
+            Leaving::Left => {
+                self.broadcast(chat_id, ResponseMessage::MemberLeft { chat_id, user });
+            }
+            Leaving::NotMember => (),
+            Leaving::OwnerWithMembers => return Err(ControllerError::OwnerLeaving),
+        }
+
+        Ok(())
+    }
+
+    /* An invite for a specific user can be used once unless `max_uses` says otherwise */
+    pub async fn create_invite(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        invitee_id: Option<Uuid>,
+        expires_at: Option<DateTime<Utc>>,
+        max_uses: Option<i32>,
+    ) -> Result<Invite, ControllerError> {
+        if expires_at.is_some_and(|expires_at| expires_at <= Utc::now())
+            || max_uses.is_some_and(|max_uses| max_uses < 1)
+        {
+            return Err(ControllerError::InvalidInvite);
+        }
+
+        ModelChat::get(&self.db, chat_id).await?;
+        if !self.member_role(chat_id, user_id).await?.can_invite() {
+            return Err(ControllerError::NotChatAdmin);
+        }
+        if let Some(invitee_id) = invitee_id {
+            ModelUser::get_by_id(&self.db, invitee_id)
+                .await
+                .map_err(user_error)?;
+        }
+
+        let max_uses = max_uses.or(invitee_id.map(|_| 1));
+        let invite =
+            ModelInvite::create(&self.db, chat_id, user_id, invitee_id, expires_at, max_uses)
+                .await?;
+
+        Ok(Invite::from_model_invite(invite))
+    }
+
+    pub async fn list_invites(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+    ) -> Result<Vec<Invite>, ControllerError> {
+        if !self.member_role(chat_id, user_id).await?.can_invite() {
+            return Err(ControllerError::NotChatAdmin);
+        }
+
+        let invites = ModelInvite::get_pending_for_chat(&self.db, chat_id, Utc::now())
+            .await?
+            .into_iter()
+            .map(Invite::from_model_invite)
+            .collect();
+
+        Ok(invites)
+    }
+
+    pub async fn revoke_invite(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        code: &str,
+    ) -> Result<(), ControllerError> {
+        if !self.member_role(chat_id, user_id).await?.can_invite() {
+            return Err(ControllerError::NotChatAdmin);
+        }
+
+        match ModelInvite::delete(&self.db, chat_id, code).await? {
+            true => Ok(()),
+            false => Err(ControllerError::InviteNotFound),
+        }
+    }
+
+    /* Members accepting again don't use the invite up */
+    pub async fn accept_invite(&self, code: &str, user: User) -> Result<Chat, ControllerError> {
+        let invite = ModelInvite::get(&self.db, code)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => ControllerError::InviteNotFound,
+                e => e.into(),
+            })?;
+        // Invites for someone else look like they don't exist
+        if invite
+            .user_id
+            .is_some_and(|invitee_id| invitee_id != user.id)
+        {
+            return Err(ControllerError::InviteNotFound);
+        }
+
+        let chat = ModelChat::get(&self.db, invite.chat_id).await?;
+        let role = chat
+            .kind
+            .joining_role()
+            .ok_or(ControllerError::DirectChat)?;
+
+        match ModelInvite::accept(&self.db, code, chat.id, user.id, role, Utc::now()).await? {
+            InviteAcceptance::Joined => self.broadcast(
+                chat.id,
+                ResponseMessage::MemberJoined {
+                    chat_id: chat.id,
+                    user,
+                },
+            ),
+            InviteAcceptance::AlreadyMember => (),
+            InviteAcceptance::Expired => return Err(ControllerError::InviteExpired),
+        }
+
+        Ok(Chat::from_model_chat(chat))
+    }
+
+    /* Admins remove members ranked below them */
+    pub async fn remove_member(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        member_id: Uuid,
+    ) -> Result<(), ControllerError> {
+        let role = self.member_role(chat_id, user_id).await?;
+        let member_role = ModelChatUser::get_role(&self.db, chat_id, member_id)
+            .await?
+            .ok_or(ControllerError::UserNotFound)?;
+        if !role.can_moderate() || member_role >= role {
+            return Err(ControllerError::NotChatAdmin);
+        }
+
+        let member = User::from_model_user(
+            ModelUser::get_by_id(&self.db, member_id)
+                .await
+                .map_err(user_error)?,
+        );
+        if ModelChatUser::delete(&self.db, chat_id, member_id).await? {
+            self.broadcast(
+                chat_id,
+                ResponseMessage::MemberLeft {
+                    chat_id,
+                    user: member,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /* Making another member owner hands the chat over, the previous owner becomes an admin */
+    pub async fn set_role(
+        &self,
+        chat_id: Uuid,
+        user: User,
+        member_id: Uuid,
+        role: ChatRole,
+    ) -> Result<(), ControllerError> {
+        if self.member_role(chat_id, user.id).await? != ChatRole::Owner {
+            return Err(ControllerError::NotChatOwner);
+        }
+        if member_id == user.id {
+            return Err(ControllerError::InvalidRole);
+        }
+
+        let member = User::from_model_user(
+            ModelUser::get_by_id(&self.db, member_id)
+                .await
+                .map_err(user_error)?,
+        );
+        if role == ChatRole::Owner {
+            if !ModelChatUser::transfer_ownership(&self.db, chat_id, user.id, member_id).await? {
+                // Either the member left or the caller handed the chat over meanwhile
+                return Err(
+                    if ModelChatUser::exists(&self.db, chat_id, member_id).await? {
+                        ControllerError::NotChatOwner
+                    } else {
+                        ControllerError::UserNotFound
+                    },
+                );
+            }
+        } else if !ModelChatUser::set_role(&self.db, chat_id, member_id, role).await? {
+            return Err(ControllerError::UserNotFound);
+        }
+
+        self.broadcast(
+            chat_id,
+            ResponseMessage::RoleChanged {
+                chat_id,
+                user: member,
+                role,
+            },
+        );
+        if role == ChatRole::Owner {
+            self.broadcast(
+                chat_id,
+                ResponseMessage::RoleChanged {
+                    chat_id,
+                    user,
+                    role: ChatRole::Admin,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /* Subscribes a connection of a member to the chat, Join is sent for their first one */
+    pub async fn connect_user(
+        &self,
+        chat_id: Uuid,
+        connection_id: Uuid,
+        user: User,
+    ) -> Result<Receiver<ResponseMessage>, ControllerError> {
+        let connections =
+            ModelPresence::connect(&self.db, connection_id, chat_id, user.id, &self.instance_id)
+                .await?
+                .ok_or(ControllerError::NotMember)?;
+        let broadcast_receiver = self.channels.subscribe(chat_id);
+
+        if connections == 1 {
+            self.broadcast(chat_id, ResponseMessage::Join { user });
+        }
+
+        Ok(broadcast_receiver)
+    }
+
+    /* Expects the receiver returned by `connect_user` to be dropped already */
+    pub async fn disconnect_user(
+        &self,
+        chat_id: Uuid,
+        connection_id: Uuid,
+        user: User,
+    ) -> Result<(), ControllerError> {
+        if ModelPresence::disconnect(&self.db, connection_id, chat_id, user.id).await? == Some(0) {
+            self.stop_typing(chat_id, &user);
+            self.broadcast(chat_id, ResponseMessage::Leave { user });
+        }
+        self.channels.release(chat_id);
+
+        Ok(())
+    }
+
+    /* Members with at least one live connection to the chat */
+    pub async fn online_users(&self, chat_id: Uuid) -> Result<Vec<User>, ControllerError> {
+        let users = ModelUser::get_online_users(&self.db, chat_id)
+            .await?
+            .into_iter()
+            .map(User::from_model_user)
+            .collect();
+
+        Ok(users)
+    }
+
+    /* Drops presence left behind by a previous run of this instance */
+    pub async fn clear_presence(&self) -> Result<(), ControllerError> {
+        // Registered before any connection, rows of unknown instances get reclaimed
+        ModelPresence::heartbeat(&self.db, &self.instance_id, Utc::now()).await?;
+        let cleared = ModelPresence::delete_for_instance(&self.db, &self.instance_id).await?;
+        if cleared > 0 {
+            tracing::warn!("cleared {} stale connections", cleared);
+        }
+
+        Ok(())
+    }
+
+    pub async fn send_message(
+        &self,
+        chat_id: Uuid,
+        id: Uuid,
+        username: String,
+        content: String,
+        client_msg_id: Option<String>,
+        reply_to: Option<Uuid>,
+    ) -> Result<ChatMessage, ControllerError> {
+        if client_msg_id
+            .as_ref()
+            .is_some_and(|client_msg_id| client_msg_id.chars().count() > MAX_CLIENT_MSG_ID_LENGTH)
+        {
+            return Err(ControllerError::InvalidClientMsgId);
+        }
+        // Retries get the stored message even if it couldn't be sent anymore
+        if let Some(client_msg_id) = &client_msg_id {
+            if let Some(message) = self
+                .retried_message(chat_id, id, client_msg_id, username.clone())
+                .await?
+            {
+                return Ok(message);
+            }
+        }
+        self.check_writable(chat_id, id, true).await?;
+
+        let quoted = match reply_to {
+            Some(reply_to) => {
+                let quoted = ModelMessage::get(&self.db, chat_id, reply_to)
+                    .await
+                    .map_err(|e| match e {
+                        sqlx::Error::RowNotFound => ControllerError::InvalidReplyTo,
+                        e => e.into(),
+                    })?;
+                if quoted.deleted_at.is_some() {
+                    return Err(ControllerError::InvalidReplyTo);
+                }
+                Some(quoted)
+            }
+            None => None,
+        };
+
+        let created = ModelMessage::create(
+            &self.db,
+            chat_id,
+            id,
+            content,
+            Utc::now(),
+            client_msg_id.clone(),
+            reply_to,
+        )
+        .await?;
+        let message = match (created, client_msg_id) {
+            (Some(message), _) => message,
+            // A concurrent send of the same message was stored first
+            (None, Some(client_msg_id)) => {
+                return self
+                    .retried_message(chat_id, id, &client_msg_id, username)
+                    .await?
+                    .ok_or(sqlx::Error::RowNotFound.into());
+            }
+            (None, None) => return Err(sqlx::Error::RowNotFound.into()),
+        };
+
+        // Senders have read their own message, the message itself tells the others
+        ModelChatUser::mark_read(&self.db, chat_id, id, message.id, message.created_at).await?;
+        let user = User {
+            id,
+            username: username.clone(),
+        };
+        self.stop_typing(chat_id, &user);
+
+        let mut message = ChatMessage::from_model_message(message, username);
+        if let Some(quoted) = quoted {
+            let author = ModelUser::get_by_id(&self.db, quoted.user_id).await?;
+            message = message.quoting(quoted, author.username);
+        }
+        self.broadcast(chat_id, ResponseMessage::Message(message.clone()));
+
+        Ok(message)
+    }
+
+    /* The stored message of an earlier send with the same client_msg_id, quoting what it replied to */
+    async fn retried_message(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        client_msg_id: &str,
+        username: String,
+    ) -> Result<Option<ChatMessage>, ControllerError> {
+        let Some(stored) =
+            ModelMessage::get_by_client_msg_id(&self.db, chat_id, user_id, client_msg_id).await?
+        else {
+            return Ok(None);
+        };
+
+        let reply_to = stored.reply_to;
+        let mut message = ChatMessage::from_model_message(stored, username);
+        if let Some(reply_to) = reply_to {
+            // The quoted message may have been deleted since
+            let quoted = ModelMessage::get(&self.db, chat_id, reply_to).await?;
+            let author = ModelUser::get_by_id(&self.db, quoted.user_id).await?;
+            message = message.quoting(quoted, author.username);
+        }
+
+        Ok(Some(message))
+    }
+
+    /* Replies to a message of the chat, which may be deleted by now */
+    pub async fn load_thread(
+        &self,
+        chat_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<Vec<ChatMessage>, ControllerError> {
+        ModelMessage::get(&self.db, chat_id, message_id)
+            .await
+            .map_err(message_error)?;
+
+        Ok(ModelMessage::get_thread(&self.db, chat_id, message_id).await?)
+    }
+
+    pub async fn edit_message(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        message_id: Uuid,
+        content: String,
+    ) -> Result<(), ControllerError> {
+        self.check_writable(chat_id, user_id, true).await?;
+        self.check_message_access(chat_id, user_id, message_id, false)
+            .await?;
+
+        let edited_at = Utc::now();
+        ModelMessage::update_content(&self.db, message_id, &content, edited_at)
+            .await
+            .map_err(message_error)?;
+
+        self.broadcast(
+            chat_id,
+            ResponseMessage::MessageEdited {
+                chat_id,
+                message_id,
+                content,
+                edited_at,
+            },
+        );
+
+        Ok(())
+    }
+
+    pub async fn delete_message(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<(), ControllerError> {
+        self.check_writable(chat_id, user_id, false).await?;
+        self.check_message_access(chat_id, user_id, message_id, true)
+            .await?;
+
+        let deleted_at = Utc::now();
+        ModelMessage::soft_delete(&self.db, message_id, deleted_at)
+            .await
+            .map_err(message_error)?;
+
+        self.broadcast(
+            chat_id,
+            ResponseMessage::MessageDeleted {
+                chat_id,
+                message_id,
+                deleted_at,
+            },
+        );
+
+        Ok(())
+    }
+
+    pub async fn pin_message(
+        &self,
+        chat_id: Uuid,
+        user: User,
+        message_id: Uuid,
+    ) -> Result<(), ControllerError> {
+        self.check_pin(chat_id, user.id, message_id).await?;
+
+        let pinned_at = Utc::now();
+        if ModelMessage::pin(&self.db, message_id, user.id, pinned_at).await? {
+            self.broadcast(
+                chat_id,
+                ResponseMessage::MessagePinned {
+                    chat_id,
+                    message_id,
+                    pinned_by: user,
+                    pinned_at,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    pub async fn unpin_message(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<(), ControllerError> {
+        self.check_pin(chat_id, user_id, message_id).await?;
+
+        if ModelMessage::unpin(&self.db, message_id).await? {
+            self.broadcast(
+                chat_id,
+                ResponseMessage::MessageUnpinned {
+                    chat_id,
+                    message_id,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn check_pin(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<(), ControllerError> {
+        self.check_writable(chat_id, user_id, false).await?;
+        if !self.member_role(chat_id, user_id).await?.can_moderate() {
+            return Err(ControllerError::NotChatAdmin);
+        }
+
+        let message = ModelMessage::get(&self.db, chat_id, message_id)
+            .await
+            .map_err(message_error)?;
+        match message.deleted_at {
+            Some(_) => Err(ControllerError::MessageNotFound),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn mark_read(
+        &self,
+        chat_id: Uuid,
+        user: User,
+        message_id: Uuid,
+    ) -> Result<(), ControllerError> {
+        // Checked first so non-members can't tell which messages exist
+        self.member_role(chat_id, user.id).await?;
+        ModelMessage::get(&self.db, chat_id, message_id)
+            .await
+            .map_err(message_error)?;
+
+        let read_at = Utc::now();
+        if ModelChatUser::mark_read(&self.db, chat_id, user.id, message_id, read_at).await? {
+            self.broadcast(
+                chat_id,
+                ResponseMessage::ReadReceipt {
+                    chat_id,
+                    user,
+                    message_id,
+                    read_at,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /* Relays a Typing event, never touches the database */
+    pub fn typing(&self, chat_id: Uuid, user: User) {
+        let now = Instant::now();
+        let update = self.typing.lock().unwrap().typed((chat_id, user.id), now);
+        if update == TypingUpdate::Throttled {
+            return;
+        }
+
+        self.broadcast(
+            chat_id,
+            ResponseMessage::Typing {
+                chat_id,
+                user: user.clone(),
+            },
+        );
+        if update == TypingUpdate::Started {
+            self.expire_typing(chat_id, user, now);
+        }
+    }
+
+    /* Sends StoppedTyping once the user sent no Typing for TYPING_TIMEOUT */
+    fn expire_typing(&self, chat_id: Uuid, user: User, started_at: Instant) {
+        let bus = self.bus.clone();
+        let typing = self.typing.clone();
+
+        tokio::spawn(async move {
+            let mut deadline = started_at + TYPING_TIMEOUT;
+            loop {
+                sleep_until(deadline).await;
+                let expiry = typing
+                    .lock()
+                    .unwrap()
+                    .expire((chat_id, user.id), Instant::now());
+                match expiry {
+                    TypingExpiry::Pending(next) => deadline = next,
+                    TypingExpiry::Expired => {
+                        bus.publish(chat_id, ResponseMessage::StoppedTyping { chat_id, user });
+                        return;
+                    }
+                    TypingExpiry::Stopped => return,
+                }
+            }
+        });
+    }
+
+    fn stop_typing(&self, chat_id: Uuid, user: &User) {
+        if self.typing.lock().unwrap().stop((chat_id, user.id)) {
+            self.broadcast(
+                chat_id,
+                ResponseMessage::StoppedTyping {
+                    chat_id,
+                    user: user.clone(),
+                },
+            );
+        }
+    }
+
+    pub async fn react(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        message_id: Uuid,
+        emoji: String,
+    ) -> Result<(), ControllerError> {
+        self.check_writable(chat_id, user_id, true).await?;
+        self.check_reaction(chat_id, message_id, &emoji).await?;
+
+        if ModelReaction::create(&self.db, message_id, user_id, &emoji).await? {
+            self.broadcast_reactions(chat_id, message_id).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn unreact(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        message_id: Uuid,
+        emoji: String,
+    ) -> Result<(), ControllerError> {
+        self.check_writable(chat_id, user_id, false).await?;
+        self.check_reaction(chat_id, message_id, &emoji).await?;
+
+        if ModelReaction::delete(&self.db, message_id, user_id, &emoji).await? {
+            self.broadcast_reactions(chat_id, message_id).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn check_reaction(
+        &self,
+        chat_id: Uuid,
+        message_id: Uuid,
+        emoji: &str,
+    ) -> Result<(), ControllerError> {
+        if emoji.is_empty() || emoji.len() > MAX_EMOJI_LENGTH {
+            return Err(ControllerError::InvalidEmoji);
+        }
+
+        let message = ModelMessage::get(&self.db, chat_id, message_id)
+            .await
+            .map_err(message_error)?;
+        match message.deleted_at {
+            Some(_) => Err(ControllerError::MessageNotFound),
+            None => Ok(()),
+        }
+    }
+
+    /* Sends the new totals so clients don't have to track who reacted */
+    async fn broadcast_reactions(
+        &self,
+        chat_id: Uuid,
+        message_id: Uuid,
+    ) -> Result<(), ControllerError> {
+        let reactions = ModelReaction::get_counts(&self.db, message_id).await?;
+        self.broadcast(
+            chat_id,
+            ResponseMessage::ReactionUpdated {
+                chat_id,
+                message_id,
+                reactions,
+            },
+        );
+
+        Ok(())
+    }
+
+    /* Messages of archived chats stay as they are, adding content also takes a role that can post */
+    async fn check_writable(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        posting: bool,
+    ) -> Result<(), ControllerError> {
+        if ModelChat::get(&self.db, chat_id).await?.archived {
+            return Err(ControllerError::ChatArchived);
+        }
+        if posting && !self.member_role(chat_id, user_id).await?.can_post() {
+            return Err(ControllerError::ReadOnly);
+        }
+
+        Ok(())
+    }
+
+    /* The author may edit a message, admins of the chat may only delete it */
+    async fn check_message_access(
+        &self,
+        chat_id: Uuid,
+        user_id: Uuid,
+        message_id: Uuid,
+        deleting: bool,
+    ) -> Result<(), ControllerError> {
+        let message = ModelMessage::get(&self.db, chat_id, message_id)
+            .await
+            .map_err(message_error)?;
+        if message.deleted_at.is_some() {
+            return Err(ControllerError::MessageNotFound);
+        }
+        if message.user_id == user_id {
+            return Ok(());
+        }
+        if !deleting {
+            return Err(ControllerError::NotAuthor);
+        }
+
+        match self.member_role(chat_id, user_id).await?.can_moderate() {
+            true => Ok(()),
+            false => Err(ControllerError::Forbidden),
+        }
+    }
+
+    async fn member_role(&self, chat_id: Uuid, user_id: Uuid) -> Result<ChatRole, ControllerError> {
+        ModelChatUser::get_role(&self.db, chat_id, user_id)
+            .await?
+            .ok_or(ControllerError::NotMember)
+    }
+
+    /* Reaches the connections to the chat on every instance */
+    fn broadcast(&self, chat_id: Uuid, message: ResponseMessage) {
+        self.bus.publish(chat_id, message);
+    }
+}
+
+/* Keeps this instance alive and reclaims the connections of instances that died */
+async fn heartbeat(db: Pool<Postgres>, instance_id: String, bus: Arc<dyn Bus>) {
+    let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = reclaim_presence(&db, &instance_id, bus.as_ref()).await {
+            tracing::error!("Failed to reclaim presence: {}", e);
+        }
+    }
+}
+
+async fn reclaim_presence(
+    db: &Pool<Postgres>,
+    instance_id: &str,
+    bus: &dyn Bus,
+) -> sqlx::Result<()> {
+    let now = Utc::now();
+    ModelPresence::heartbeat(db, instance_id, now).await?;
+
+    let mut reclaimed = ModelPresence::reclaim(db, now - INSTANCE_TIMEOUT).await?;
+    reclaimed.sort_by_key(|presence| (presence.chat_id, presence.user_id));
+    reclaimed.dedup_by_key(|presence| (presence.chat_id, presence.user_id));
+    if !reclaimed.is_empty() {
+        tracing::warn!(
+            "reclaimed the connections of {} dead members",
+            reclaimed.len()
+        );
+    }
+
+    // Members who are still connected through a live instance stay online
+    for presence in reclaimed {
+        if ModelPresence::get_connections(db, presence.chat_id, presence.user_id).await? == 0 {
+            let user = User::from_model_user(ModelUser::get_by_id(db, presence.user_id).await?);
+            bus.publish(presence.chat_id, ResponseMessage::Leave { user });
+        }
+    }
+
+    Ok(())
+}
+
+fn message_error(e: sqlx::Error) -> ControllerError {
+    match e {
+        sqlx::Error::RowNotFound => ControllerError::MessageNotFound,
+        e => e.into(),
+    }
+}
+
+fn user_error(e: sqlx::Error) -> ControllerError {
+    match e {
+        sqlx::Error::RowNotFound => ControllerError::UserNotFound,
+        e => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::join_all;
+    use sqlx::PgPool;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use super::*;
+    use crate::{
+        config::TokenMode,
+        test_util::{create_user, test_config, test_db, TestDb},
+    };
+
+    async fn setup() -> (Controller, TestDb) {
+        let db = test_db().await;
+        let controller = Controller::new(db.clone(), &test_config(TokenMode::Session));
+
+        (controller, db)
+    }
+
+    async fn history_len(db: &PgPool, chat_id: Uuid) -> usize {
+        ModelMessage::get_chat_history(db, chat_id, None, 100)
+            .await
+            .unwrap()
+            .messages
+            .len()
+    }
+
+    async fn post(controller: &Controller, chat_id: Uuid, user: &ModelUser) -> Uuid {
+        controller
+            .send_message(
+                chat_id,
+                user.id,
+                user.username.clone(),
+                "hi".into(),
+                None,
+                None,
+            )
+            .await
+            .unwrap()
+            .id()
+    }
+
+    async fn add_member(db: &PgPool, chat_id: Uuid, user: &ModelUser, role: ChatRole) -> User {
+        ModelChatUser::create(db, chat_id, user.id, role)
+            .await
+            .unwrap();
+
+        User {
+            id: user.id,
+            username: user.username.clone(),
+        }
+    }
+
+    /* Presence events only, skipping whatever else the chat broadcast */
+    fn presence_events(events: &mut Receiver<ResponseMessage>) -> Vec<&'static str> {
+        let mut presence = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            match event {
+                ResponseMessage::Join { .. } => presence.push("join"),
+                ResponseMessage::Leave { .. } => presence.push("leave"),
+                _ => (),
+            }
+        }
+
+        presence
+    }
+
+    #[tokio::test]
+    async fn retried_message_is_stored_and_broadcast_once() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let mut events = controller.channels.subscribe(chat.id);
+
+        let send = || {
+            controller.send_message(
+                chat.id,
+                alice.id,
+                "alice".into(),
+                "hi".into(),
+                Some("retry-1".into()),
+                None,
+            )
+        };
+        let first = send().await.unwrap();
+        let retry = send().await.unwrap();
+
+        assert_eq!(first.id(), retry.id());
+        assert_eq!(history_len(&db, chat.id).await, 1);
+        assert!(matches!(events.try_recv(), Ok(ResponseMessage::Message(_))));
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    #[tokio::test]
+    async fn retries_are_acked_after_the_chat_changed() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let send = || {
+            controller.send_message(
+                chat.id,
+                alice.id,
+                "alice".into(),
+                "hi".into(),
+                Some("retry-1".into()),
+                None,
+            )
+        };
+        let first = send().await.unwrap();
+
+        ModelChatUser::set_role(&db, chat.id, alice.id, ChatRole::ReadOnly)
+            .await
+            .unwrap();
+        assert_eq!(send().await.unwrap().id(), first.id());
+
+        let archive = ChatChanges {
+            archived: Some(true),
+            ..Default::default()
+        };
+        ModelChat::update(&db, chat.id, &archive).await.unwrap();
+        assert_eq!(send().await.unwrap().id(), first.id());
+        assert_eq!(history_len(&db, chat.id).await, 1);
+    }
+
+    #[tokio::test]
+    async fn retries_quote_what_the_stored_message_replied_to() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let first_id = post(&controller, chat.id, &alice).await;
+        let second_id = post(&controller, chat.id, &alice).await;
+        let send = |reply_to| {
+            controller.send_message(
+                chat.id,
+                alice.id,
+                "alice".into(),
+                "reply".into(),
+                Some("retry-1".into()),
+                reply_to,
+            )
+        };
+
+        let sent = send(Some(first_id)).await.unwrap();
+        let retried = send(Some(second_id)).await.unwrap();
+        let without_reply = send(None).await.unwrap();
+
+        let quote = |message: &ChatMessage| {
+            let json = serde_json::to_value(message).unwrap();
+            (json["reply_to"].clone(), json["quoted_content"].clone())
+        };
+        assert_eq!(quote(&sent).0, serde_json::json!(first_id));
+        assert_eq!(quote(&retried), quote(&sent));
+        assert_eq!(quote(&without_reply), quote(&sent));
+    }
+
+    #[tokio::test]
+    async fn client_msg_id_is_scoped_to_the_sender() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let bob = create_user(&db, "bob").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        ModelChatUser::create(&db, chat.id, bob.id, ChatRole::Member)
+            .await
+            .unwrap();
+
+        for user in [&alice, &bob] {
+            controller
+                .send_message(
+                    chat.id,
+                    user.id,
+                    user.username.clone(),
+                    "hi".into(),
+                    Some("same".into()),
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(history_len(&db, chat.id).await, 2);
+    }
+
+    #[tokio::test]
+    async fn messages_without_client_msg_id_are_never_merged() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+
+        for _ in 0..2 {
+            controller
+                .send_message(chat.id, alice.id, "alice".into(), "hi".into(), None, None)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(history_len(&db, chat.id).await, 2);
+    }
+
+    #[tokio::test]
+    async fn client_msg_id_length_is_limited() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+
+        let result = controller
+            .send_message(
+                chat.id,
+                alice.id,
+                "alice".into(),
+                "hi".into(),
+                Some("x".repeat(MAX_CLIENT_MSG_ID_LENGTH + 1)),
+                None,
+            )
+            .await;
+
+        assert!(matches!(result, Err(ControllerError::InvalidClientMsgId)));
+        assert_eq!(history_len(&db, chat.id).await, 0);
+    }
+
+    #[tokio::test]
+    async fn messages_of_archived_chats_stay_as_they_are() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let message_id = post(&controller, chat.id, &alice).await;
+        controller
+            .react(chat.id, alice.id, message_id, "👍".into())
+            .await
+            .unwrap();
+        let archive = ChatChanges {
+            archived: Some(true),
+            ..Default::default()
+        };
+        ModelChat::update(&db, chat.id, &archive).await.unwrap();
+
+        let edit = controller
+            .edit_message(chat.id, alice.id, message_id, "edited".into())
+            .await;
+        let react = controller
+            .react(chat.id, alice.id, message_id, "🎉".into())
+            .await;
+        let unreact = controller
+            .unreact(chat.id, alice.id, message_id, "👍".into())
+            .await;
+        let delete = controller
+            .delete_message(chat.id, alice.id, message_id)
+            .await;
+
+        for result in [edit, react, unreact, delete] {
+            assert!(matches!(result, Err(ControllerError::ChatArchived)));
+        }
+    }
+
+    #[tokio::test]
+    async fn read_only_members_cant_edit_or_react() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let message_id = post(&controller, chat.id, &alice).await;
+        ModelChatUser::set_role(&db, chat.id, alice.id, ChatRole::ReadOnly)
+            .await
+            .unwrap();
+
+        let edit = controller
+            .edit_message(chat.id, alice.id, message_id, "edited".into())
+            .await;
+        let react = controller
+            .react(chat.id, alice.id, message_id, "👍".into())
+            .await;
+        assert!(matches!(edit, Err(ControllerError::ReadOnly)));
+        assert!(matches!(react, Err(ControllerError::ReadOnly)));
+
+        // Taking back what they said is still fine
+        controller
+            .delete_message(chat.id, alice.id, message_id)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn threads_are_loaded_for_messages_of_the_chat_only() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let other = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let root = post(&controller, chat.id, &alice).await;
+        let reply = controller
+            .send_message(
+                chat.id,
+                alice.id,
+                "alice".into(),
+                "re".into(),
+                None,
+                Some(root),
+            )
+            .await
+            .unwrap();
+
+        let thread = controller.load_thread(chat.id, root).await.unwrap();
+        assert_eq!(
+            thread.iter().map(ChatMessage::id).collect::<Vec<_>>(),
+            vec![reply.id()]
+        );
+        assert!(matches!(
+            controller.load_thread(other.id, root).await,
+            Err(ControllerError::MessageNotFound)
+        ));
+        assert!(matches!(
+            controller.load_thread(chat.id, Uuid::new_v4()).await,
+            Err(ControllerError::MessageNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn replies_to_deleted_messages_are_rejected() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let root = post(&controller, chat.id, &alice).await;
+        controller
+            .delete_message(chat.id, alice.id, root)
+            .await
+            .unwrap();
+
+        let result = controller
+            .send_message(
+                chat.id,
+                alice.id,
+                "alice".into(),
+                "re".into(),
+                None,
+                Some(root),
+            )
+            .await;
+
+        assert!(matches!(result, Err(ControllerError::InvalidReplyTo)));
+    }
+
+    #[tokio::test]
+    async fn senders_have_read_their_own_messages() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let bob = create_user(&db, "bob").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        ModelChatUser::create(&db, chat.id, bob.id, ChatRole::Member)
+            .await
+            .unwrap();
+        post(&controller, chat.id, &bob).await;
+        post(&controller, chat.id, &alice).await;
+
+        let unread = |user_id| {
+            let db = db.clone();
+            async move { ModelChatUser::get_user_chats(&db, user_id).await.unwrap()[0].unread_count }
+        };
+        // Answering marks everything before the answer as read too
+        assert_eq!(unread(alice.id).await, 0);
+        assert_eq!(unread(bob.id).await, 1);
+    }
+
+    #[tokio::test]
+    async fn only_members_connect_to_a_chat() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let bob = create_user(&db, "bob").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let bob = User::from_model_user(bob);
+
+        let result = controller
+            .connect_user(chat.id, Uuid::new_v4(), bob.clone())
+            .await;
+
+        assert!(matches!(result, Err(ControllerError::NotMember)));
+        assert!(controller.online_users(chat.id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn presence_follows_the_first_and_last_connection() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let bob = create_user(&db, "bob").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let bob = add_member(&db, chat.id, &bob, ChatRole::Member).await;
+        let mut events = controller.channels.subscribe(chat.id);
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let first_receiver = controller
+            .connect_user(chat.id, first, bob.clone())
+            .await
+            .unwrap();
+        let second_receiver = controller
+            .connect_user(chat.id, second, bob.clone())
+            .await
+            .unwrap();
+        assert_eq!(presence_events(&mut events), vec!["join"]);
+        assert_eq!(controller.online_users(chat.id).await.unwrap().len(), 1);
+
+        drop(first_receiver);
+        controller
+            .disconnect_user(chat.id, first, bob.clone())
+            .await
+            .unwrap();
+        assert!(presence_events(&mut events).is_empty());
+
+        drop(second_receiver);
+        controller
+            .disconnect_user(chat.id, second, bob.clone())
+            .await
+            .unwrap();
+        assert_eq!(presence_events(&mut events), vec!["leave"]);
+        assert!(controller.online_users(chat.id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn membership_outlives_connections_until_leaving() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let bob = create_user(&db, "bob").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let bob = add_member(&db, chat.id, &bob, ChatRole::Member).await;
+        let connection_id = Uuid::new_v4();
+
+        let receiver = controller
+            .connect_user(chat.id, connection_id, bob.clone())
+            .await
+            .unwrap();
+        drop(receiver);
+        controller
+            .disconnect_user(chat.id, connection_id, bob.clone())
+            .await
+            .unwrap();
+        assert!(ModelChatUser::exists(&db, chat.id, bob.id).await.unwrap());
+
+        controller.leave_chat(chat.id, bob.clone()).await.unwrap();
+        let result = controller
+            .connect_user(chat.id, Uuid::new_v4(), bob.clone())
+            .await;
+        assert!(matches!(result, Err(ControllerError::NotMember)));
+    }
+
+    #[tokio::test]
+    async fn concurrent_connections_join_and_leave_once() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let bob = create_user(&db, "bob").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let bob = add_member(&db, chat.id, &bob, ChatRole::Member).await;
+        let mut events = controller.channels.subscribe(chat.id);
+        let connections: Vec<Uuid> = (0..8).map(|_| Uuid::new_v4()).collect();
+
+        let receivers = join_all(
+            connections
+                .iter()
+                .map(|id| controller.connect_user(chat.id, *id, bob.clone())),
+        )
+        .await;
+        assert!(receivers.iter().all(Result::is_ok));
+        drop(receivers);
+        assert_eq!(presence_events(&mut events), vec!["join"]);
+
+        let results = join_all(
+            connections
+                .iter()
+                .map(|id| controller.disconnect_user(chat.id, *id, bob.clone())),
+        )
+        .await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(presence_events(&mut events), vec!["leave"]);
+    }
+
+    #[tokio::test]
+    async fn connections_of_dead_instances_are_reclaimed() {
+        let (controller, db) = setup().await;
+        controller.clear_presence().await.unwrap();
+        let alice = create_user(&db, "alice").await;
+        let bob = create_user(&db, "bob").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let alice = User::from_model_user(alice);
+        add_member(&db, chat.id, &bob, ChatRole::Member).await;
+        let _receiver = controller
+            .connect_user(chat.id, Uuid::new_v4(), alice.clone())
+            .await
+            .unwrap();
+        // Bob is connected through an instance that stopped sending heartbeats
+        ModelPresence::heartbeat(&db, "dead", Utc::now() - INSTANCE_TIMEOUT * 2)
+            .await
+            .unwrap();
+        ModelPresence::connect(&db, Uuid::new_v4(), chat.id, bob.id, "dead")
+            .await
+            .unwrap();
+        let mut events = controller.channels.subscribe(chat.id);
+
+        reclaim_presence(&db, &controller.instance_id, controller.bus.as_ref())
+            .await
+            .unwrap();
+
+        let online: Vec<Uuid> = controller
+            .online_users(chat.id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|user| user.id)
+            .collect();
+        assert_eq!(online, vec![alice.id]);
+        assert_eq!(presence_events(&mut events), vec!["leave"]);
+    }
+
+    #[tokio::test]
+    async fn reopening_a_direct_chat_brings_back_the_caller_only() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let bob = create_user(&db, "bob").await;
+        let (alice_user, bob_user) = (
+            User::from_model_user(alice.clone()),
+            User::from_model_user(bob.clone()),
+        );
+
+        let chat = controller
+            .open_direct(alice_user.clone(), bob.id)
+            .await
+            .unwrap();
+        let role = |user_id| ModelChatUser::get_role(&db, chat.id, user_id);
+        assert_eq!(role(alice.id).await.unwrap(), Some(ChatRole::Member));
+        assert_eq!(role(bob.id).await.unwrap(), Some(ChatRole::Member));
+
+        controller
+            .leave_chat(chat.id, alice_user.clone())
+            .await
+            .unwrap();
+        controller.leave_chat(chat.id, bob_user).await.unwrap();
+        let mut events = controller.channels.subscribe(chat.id);
+
+        let reopened = controller.open_direct(alice_user, bob.id).await.unwrap();
+        assert_eq!(reopened.id, chat.id);
+        assert_eq!(role(alice.id).await.unwrap(), Some(ChatRole::Member));
+        assert_eq!(role(bob.id).await.unwrap(), None);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(ResponseMessage::MemberJoined { user, .. }) if user.id == alice.id
+        ));
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    }
+
+    fn outcome<T>(result: Result<T, ControllerError>) -> Result<(), ErrorCode> {
+        result.map(|_| ()).map_err(|e| e.code())
+    }
+
+    #[tokio::test]
+    async fn roles_gate_what_members_can_do() {
+        let (controller, db) = setup().await;
+        let owner = create_user(&db, "owner").await;
+        let author = create_user(&db, "author").await;
+        let chat = controller
+            .create_chat(owner.id, ChatKind::Group)
+            .await
+            .unwrap();
+        add_member(&db, chat.id, &author, ChatRole::Member).await;
+
+        let forbidden = Err(ErrorCode::Forbidden);
+        let cases = [
+            (None, Err(ErrorCode::NotMember), Err(ErrorCode::NotMember)),
+            (
+                Some(ChatRole::ReadOnly),
+                Err(ErrorCode::Forbidden),
+                forbidden,
+            ),
+            (Some(ChatRole::Member), Ok(()), forbidden),
+            (Some(ChatRole::Admin), Ok(()), Ok(())),
+            (Some(ChatRole::Owner), Ok(()), Ok(())),
+        ];
+        for (i, (role, can_post, can_moderate)) in cases.into_iter().enumerate() {
+            let user = match role {
+                Some(ChatRole::Owner) => owner.clone(),
+                _ => create_user(&db, &format!("user{}", i)).await,
+            };
+            if let Some(role) = role.filter(|role| *role != ChatRole::Owner) {
+                add_member(&db, chat.id, &user, role).await;
+            }
+            let message_id = post(&controller, chat.id, &author).await;
+            let victim = create_user(&db, &format!("victim{}", i)).await;
+            add_member(&db, chat.id, &victim, ChatRole::ReadOnly).await;
+            let changes = ChatChanges {
+                name: Some("renamed".into()),
+                ..Default::default()
+            };
+
+            let posted = controller
+                .send_message(
+                    chat.id,
+                    user.id,
+                    user.username.clone(),
+                    "hi".into(),
+                    None,
+                    None,
+                )
+                .await;
+            assert_eq!(outcome(posted), can_post, "{:?} posting", role);
+            let invite = controller
+                .create_invite(chat.id, user.id, None, None, None)
+                .await;
+            assert_eq!(outcome(invite), can_moderate, "{:?} inviting", role);
+            let updated = controller.update_chat(chat.id, user.id, changes).await;
+            assert_eq!(outcome(updated), can_moderate, "{:?} updating", role);
+            let pinned = controller
+                .pin_message(chat.id, User::from_model_user(user.clone()), message_id)
+                .await;
+            assert_eq!(outcome(pinned), can_moderate, "{:?} pinning", role);
+            let deleted = controller
+                .delete_message(chat.id, user.id, message_id)
+                .await;
+            assert_eq!(outcome(deleted), can_moderate, "{:?} deleting", role);
+            let removed = controller.remove_member(chat.id, user.id, victim.id).await;
+            assert_eq!(outcome(removed), can_moderate, "{:?} removing", role);
+        }
+    }
+
+    #[tokio::test]
+    async fn pins_of_archived_chats_stay_as_they_are() {
+        let (controller, db) = setup().await;
+        let owner = create_user(&db, "owner").await;
+        let owner_user = User::from_model_user(owner.clone());
+        let chat = controller
+            .create_chat(owner.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let pinned_id = post(&controller, chat.id, &owner).await;
+        let unpinned_id = post(&controller, chat.id, &owner).await;
+        controller
+            .pin_message(chat.id, owner_user.clone(), pinned_id)
+            .await
+            .unwrap();
+        let archive = ChatChanges {
+            archived: Some(true),
+            ..Default::default()
+        };
+        ModelChat::update(&db, chat.id, &archive).await.unwrap();
+
+        let pin = controller
+            .pin_message(chat.id, owner_user, unpinned_id)
+            .await;
+        let unpin = controller.unpin_message(chat.id, owner.id, pinned_id).await;
+
+        assert_eq!(outcome(pin), Err(ErrorCode::ChatArchived));
+        assert_eq!(outcome(unpin), Err(ErrorCode::ChatArchived));
+    }
+
+    #[tokio::test]
+    async fn ownership_moves_in_one_step() {
+        let (controller, db) = setup().await;
+        let owner = create_user(&db, "owner").await;
+        let member = create_user(&db, "member").await;
+        let chat = controller
+            .create_chat(owner.id, ChatKind::Group)
+            .await
+            .unwrap();
+        add_member(&db, chat.id, &member, ChatRole::Member).await;
+        let owner_user = User::from_model_user(owner.clone());
+        let role = |user_id| ModelChatUser::get_role(&db, chat.id, user_id);
+
+        let leaving = controller.leave_chat(chat.id, owner_user.clone()).await;
+        assert!(matches!(leaving, Err(ControllerError::OwnerLeaving)));
+
+        controller
+            .set_role(chat.id, owner_user.clone(), member.id, ChatRole::Owner)
+            .await
+            .unwrap();
+        assert_eq!(role(owner.id).await.unwrap(), Some(ChatRole::Admin));
+        assert_eq!(role(member.id).await.unwrap(), Some(ChatRole::Owner));
+
+        let again = controller
+            .set_role(chat.id, owner_user.clone(), member.id, ChatRole::Owner)
+            .await;
+        assert!(matches!(again, Err(ControllerError::NotChatOwner)));
+
+        // The former owner can leave now, and the new one once they are alone
+        controller.leave_chat(chat.id, owner_user).await.unwrap();
+        controller
+            .leave_chat(chat.id, User::from_model_user(member.clone()))
+            .await
+            .unwrap();
+        assert_eq!(role(member.id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn ownership_is_not_handed_to_a_non_member() {
+        let (controller, db) = setup().await;
+        let owner = create_user(&db, "owner").await;
+        let outsider = create_user(&db, "outsider").await;
+        let chat = controller
+            .create_chat(owner.id, ChatKind::Group)
+            .await
+            .unwrap();
+
+        let result = controller
+            .set_role(
+                chat.id,
+                User::from_model_user(owner.clone()),
+                outsider.id,
+                ChatRole::Owner,
+            )
+            .await;
+
+        assert!(matches!(result, Err(ControllerError::UserNotFound)));
+        assert_eq!(
+            ModelChatUser::get_role(&db, chat.id, owner.id)
+                .await
+                .unwrap(),
+            Some(ChatRole::Owner)
+        );
+    }
+
+    #[tokio::test]
+    async fn private_chats_are_joined_with_an_invite_only() {
+        let (controller, db) = setup().await;
+        let owner = create_user(&db, "owner").await;
+        let outsider = create_user(&db, "outsider").await;
+        let chat = controller
+            .create_chat(owner.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let outsider_user = User::from_model_user(outsider.clone());
+
+        let joined = controller.join_chat(chat.id, outsider_user.clone()).await;
+        assert_eq!(outcome(joined), Err(ErrorCode::Forbidden));
+        assert!(!ModelChatUser::exists(&db, chat.id, outsider.id)
+            .await
+            .unwrap());
+
+        let public = ChatChanges {
+            public: Some(true),
+            ..Default::default()
+        };
+        controller
+            .update_chat(chat.id, owner.id, public)
+            .await
+            .unwrap();
+        controller.join_chat(chat.id, outsider_user).await.unwrap();
+        assert_eq!(
+            ModelChatUser::get_role(&db, chat.id, outsider.id)
+                .await
+                .unwrap(),
+            Some(ChatRole::Member)
+        );
+    }
+
+    #[tokio::test]
+    async fn invites_count_the_users_they_add() {
+        let (controller, db) = setup().await;
+        let owner = create_user(&db, "owner").await;
+        let alice = User::from_model_user(create_user(&db, "alice").await);
+        let bob = User::from_model_user(create_user(&db, "bob").await);
+        let chat = controller
+            .create_chat(owner.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let invite = controller
+            .create_invite(chat.id, owner.id, None, None, Some(1))
+            .await
+            .unwrap();
+        let uses = || async { ModelInvite::get(&db, &invite.code).await.unwrap().uses };
+
+        controller
+            .accept_invite(&invite.code, alice.clone())
+            .await
+            .unwrap();
+        // Accepting again as a member doesn't count
+        controller
+            .accept_invite(&invite.code, alice.clone())
+            .await
+            .unwrap();
+        assert_eq!(uses().await, 1);
+
+        let used_up = controller.accept_invite(&invite.code, bob.clone()).await;
+        assert!(matches!(used_up, Err(ControllerError::InviteExpired)));
+        assert!(!ModelChatUser::exists(&db, chat.id, bob.id).await.unwrap());
+        assert_eq!(uses().await, 1);
+
+        let personal = controller
+            .create_invite(chat.id, owner.id, Some(alice.id), None, None)
+            .await
+            .unwrap();
+        let someone_else = controller.accept_invite(&personal.code, bob).await;
+        assert!(matches!(someone_else, Err(ControllerError::InviteNotFound)));
+    }
+
+    #[tokio::test]
+    async fn joining_waits_for_a_leaving_owner() {
+        let (controller, db) = setup().await;
+        let owner = create_user(&db, "owner").await;
+        let joiner = create_user(&db, "joiner").await;
+        let chat = controller
+            .create_chat(owner.id, ChatKind::Group)
+            .await
+            .unwrap();
+
+        // Holds the lock leave_chat takes while it checks for other members
+        let mut leaving = db.begin().await.unwrap();
+        sqlx::query("SELECT 1 FROM chats WHERE id = $1 FOR UPDATE")
+            .bind(chat.id)
+            .execute(&mut *leaving)
+            .await
+            .unwrap();
+        let join = tokio::spawn({
+            let db = db.clone();
+            async move { ModelChatUser::create(&db, chat.id, joiner.id, ChatRole::Member).await }
+        });
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!join.is_finished());
+        leaving.rollback().await.unwrap();
+        assert!(join.await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn only_members_mark_messages_read() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let outsider = User::from_model_user(create_user(&db, "outsider").await);
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let message_id = post(&controller, chat.id, &alice).await;
+
+        let existing = controller
+            .mark_read(chat.id, outsider.clone(), message_id)
+            .await;
+        let missing = controller
+            .mark_read(chat.id, outsider, Uuid::new_v4())
+            .await;
+
+        assert!(matches!(existing, Err(ControllerError::NotMember)));
+        assert!(matches!(missing, Err(ControllerError::NotMember)));
+    }
+
+    #[tokio::test]
+    async fn chat_details_are_set_kept_and_cleared() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let mut events = controller.channels.subscribe(chat.id);
+
+        let set = ChatChanges {
+            name: Some("Robins".into()),
+            topic: Some("Birds".into()),
+            avatar_url: Some("https://example.com/robin.png".into()),
+            ..Default::default()
+        };
+        controller
+            .update_chat(chat.id, alice.id, set)
+            .await
+            .unwrap();
+
+        // Missing fields stay as they are, empty ones are cleared
+        let clear = ChatChanges {
+            topic: Some("".into()),
+            avatar_url: Some("".into()),
+            ..Default::default()
+        };
+        let updated = controller
+            .update_chat(chat.id, alice.id, clear)
+            .await
+            .unwrap();
+
+        assert_eq!(updated.name.as_deref(), Some("Robins"));
+        assert_eq!(updated.topic, None);
+        assert_eq!(updated.avatar_url, None);
+        let stored = ModelChat::get(&db, chat.id).await.unwrap();
+        assert_eq!(stored.name.as_deref(), Some("Robins"));
+        assert_eq!(stored.topic, None);
+        assert_eq!(stored.avatar_url, None);
+
+        assert!(matches!(
+            events.try_recv(),
+            Ok(ResponseMessage::ChatUpdated { .. })
+        ));
+        match events.try_recv() {
+            Ok(ResponseMessage::ChatUpdated { chat }) => assert_eq!(chat.topic, None),
+            _ => panic!("expected a ChatUpdated event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_details_are_limited_in_length() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+
+        for changes in [
+            ChatChanges {
+                name: Some("n".repeat(MAX_CHAT_NAME_LENGTH + 1)),
+                ..Default::default()
+            },
+            ChatChanges {
+                topic: Some("t".repeat(MAX_CHAT_TOPIC_LENGTH + 1)),
+                ..Default::default()
+            },
+            ChatChanges {
+                avatar_url: Some("a".repeat(MAX_AVATAR_URL_LENGTH + 1)),
+                ..Default::default()
+            },
+        ] {
+            let result = controller.update_chat(chat.id, alice.id, changes).await;
+            assert!(matches!(result, Err(ControllerError::InvalidChatChanges)));
+        }
+
+        let name = ChatChanges {
+            name: Some("n".repeat(MAX_CHAT_NAME_LENGTH)),
+            ..Default::default()
+        };
+        controller
+            .update_chat(chat.id, alice.id, name)
+            .await
+            .unwrap();
+    }
+
+    /* Totals of the ReactionUpdated events broadcast so far */
+    fn reaction_counts(events: &mut Receiver<ResponseMessage>) -> Vec<Vec<(String, i64)>> {
+        let mut counts = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let ResponseMessage::ReactionUpdated { reactions, .. } = event {
+                counts.push(
+                    reactions
+                        .into_iter()
+                        .map(|reaction| (reaction.emoji, reaction.count))
+                        .collect(),
+                );
+            }
+        }
+
+        counts
+    }
+
+    #[tokio::test]
+    async fn reactions_are_counted_per_emoji_and_toggled_per_user() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let bob = create_user(&db, "bob").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        add_member(&db, chat.id, &bob, ChatRole::Member).await;
+        let message_id = post(&controller, chat.id, &alice).await;
+        let mut events = controller.channels.subscribe(chat.id);
+        let react = |user: &ModelUser, emoji: &str| {
+            controller.react(chat.id, user.id, message_id, emoji.into())
+        };
+
+        react(&alice, "👍").await.unwrap();
+        react(&bob, "👍").await.unwrap();
+        react(&bob, "🎉").await.unwrap();
+        // Reacting twice with the same emoji changes nothing
+        react(&alice, "👍").await.unwrap();
+        assert_eq!(
+            reaction_counts(&mut events),
+            vec![
+                vec![("👍".to_string(), 1)],
+                vec![("👍".to_string(), 2)],
+                vec![("👍".to_string(), 2), ("🎉".to_string(), 1)],
+            ]
+        );
+
+        let unreact = |user: &ModelUser, emoji: &str| {
+            controller.unreact(chat.id, user.id, message_id, emoji.into())
+        };
+        unreact(&bob, "👍").await.unwrap();
+        unreact(&bob, "👍").await.unwrap();
+        unreact(&bob, "🎉").await.unwrap();
+        assert_eq!(
+            reaction_counts(&mut events),
+            vec![
+                vec![("👍".to_string(), 1), ("🎉".to_string(), 1)],
+                vec![("👍".to_string(), 1)],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reactions_need_a_valid_emoji_and_a_live_message() {
+        let (controller, db) = setup().await;
+        let alice = create_user(&db, "alice").await;
+        let chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let other_chat = controller
+            .create_chat(alice.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let message_id = post(&controller, chat.id, &alice).await;
+        let react = |chat_id: Uuid, message_id: Uuid, emoji: &str| {
+            controller.react(chat_id, alice.id, message_id, emoji.into())
+        };
+
+        let empty = react(chat.id, message_id, "").await;
+        let too_long = react(chat.id, message_id, &"👍".repeat(MAX_EMOJI_LENGTH)).await;
+        let wrong_chat = react(other_chat.id, message_id, "👍").await;
+        controller
+            .delete_message(chat.id, alice.id, message_id)
+            .await
+            .unwrap();
+        let deleted = react(chat.id, message_id, "👍").await;
+
+        assert!(matches!(empty, Err(ControllerError::InvalidEmoji)));
+        assert!(matches!(too_long, Err(ControllerError::InvalidEmoji)));
+        assert!(matches!(wrong_chat, Err(ControllerError::MessageNotFound)));
+        assert!(matches!(deleted, Err(ControllerError::MessageNotFound)));
+    }
+
+    #[test]
+    fn typing_is_relayed_once_per_throttle_interval() {
+        let mut typing = TypingUsers::default();
+        let key = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+
+        assert_eq!(typing.typed(key, start), TypingUpdate::Started);
+        assert_eq!(
+            typing.typed(key, start + Duration::from_secs(1)),
+            TypingUpdate::Throttled
+        );
+        assert_eq!(
+            typing.typed(key, start + TYPING_THROTTLE),
+            TypingUpdate::Relayed
+        );
+        assert_eq!(
+            typing.typed(key, start + TYPING_THROTTLE + Duration::from_secs(1)),
+            TypingUpdate::Throttled
+        );
+        // Other users and chats are throttled on their own
+        assert_eq!(
+            typing.typed((key.0, Uuid::new_v4()), start),
+            TypingUpdate::Started
+        );
+    }
+
+    #[test]
+    fn typing_expires_after_the_last_event() {
+        let mut typing = TypingUsers::default();
+        let key = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+        let last_seen = start + Duration::from_secs(2);
+
+        typing.typed(key, start);
+        // Throttled events still keep the user typing
+        typing.typed(key, last_seen);
+
+        assert_eq!(
+            typing.expire(key, start + TYPING_TIMEOUT),
+            TypingExpiry::Pending(last_seen + TYPING_TIMEOUT)
+        );
+        assert_eq!(
+            typing.expire(key, last_seen + TYPING_TIMEOUT),
+            TypingExpiry::Expired
+        );
+        assert_eq!(
+            typing.expire(key, last_seen + TYPING_TIMEOUT),
+            TypingExpiry::Stopped
+        );
+        assert_eq!(
+            typing.typed(key, last_seen + TYPING_TIMEOUT),
+            TypingUpdate::Started
+        );
+    }
+
+    #[test]
+    fn stopped_typing_is_not_expired_again() {
+        let mut typing = TypingUsers::default();
+        let key = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+
+        typing.typed(key, start);
+
+        assert!(typing.stop(key));
+        assert!(!typing.stop(key));
+        assert_eq!(
+            typing.expire(key, start + TYPING_TIMEOUT),
+            TypingExpiry::Stopped
+        );
+    }
+}
@@ -7,9 +7,18 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
-use serde::Deserialize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::{models::ModelUser, AppState, AuthorisedUser};
+use crate::{
+    auth::{self, AuthError, AuthSession},
+    models::{ModelSession, ModelUser},
+    password::{
+        hash_password, verify_dummy_password, verify_password, PasswordError, Verification,
+    },
+    AppState, AuthorisedUser,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum LoginError {
@@ -17,6 +26,12 @@ pub enum LoginError {
     // ValidationError(String),
     #[error(transparent)]
     NotFoundError(#[from] sqlx::Error),
+    #[error("LoginError: invalid password")]
+    InvalidPassword,
+    #[error(transparent)]
+    PasswordError(#[from] PasswordError),
+    #[error(transparent)]
+    AuthError(#[from] AuthError),
 }
 
 impl IntoResponse for LoginError {
@@ -29,6 +44,12 @@ impl IntoResponse for LoginError {
                     _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
                 }
             }
+            Self::InvalidPassword => StatusCode::UNAUTHORIZED.into_response(),
+            Self::PasswordError(e) => {
+                tracing::error!("{}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            Self::AuthError(e) => e.into_response(),
         }
     }
 }
@@ -37,6 +58,103 @@ impl IntoResponse for LoginError {
 pub struct Login {
     pub username: String,
     pub password: String,
+    // Name of the device the session is created for
+    #[serde(default)]
+    pub device: Option<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RegisterError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("Username is already taken")]
+    UsernameTaken,
+    #[error(transparent)]
+    DatabaseError(sqlx::Error),
+    #[error(transparent)]
+    PasswordError(#[from] PasswordError),
+    #[error(transparent)]
+    AuthError(#[from] AuthError),
+}
+
+impl From<sqlx::Error> for RegisterError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::Database(ref db_error) if db_error.is_unique_violation() => {
+                Self::UsernameTaken
+            }
+            e => Self::DatabaseError(e),
+        }
+    }
+}
+
+impl IntoResponse for RegisterError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::ValidationError(message) => (StatusCode::BAD_REQUEST, message).into_response(),
+            Self::UsernameTaken => (StatusCode::CONFLICT, self.to_string()).into_response(),
+            Self::DatabaseError(e) => {
+                tracing::error!("{}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            Self::PasswordError(e) => {
+                tracing::error!("{}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+            Self::AuthError(e) => e.into_response(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Register {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub device: Option<String>,
+}
+
+impl Register {
+    fn validate(&self) -> Result<(), RegisterError> {
+        let username_length = self.username.chars().count();
+        if !(3..=32).contains(&username_length) {
+            return Err(RegisterError::ValidationError(
+                "Username must be between 3 and 32 characters long".into(),
+            ));
+        }
+        if !self
+            .username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        {
+            return Err(RegisterError::ValidationError(
+                "Username may only contain letters, digits, '_', '-' and '.'".into(),
+            ));
+        }
+
+        let password_length = self.password.chars().count();
+        if !(8..=128).contains(&password_length) {
+            return Err(RegisterError::ValidationError(
+                "Password must be between 8 and 128 characters long".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[debug_handler]
+pub async fn register(
+    State(state): State<Arc<AppState>>,
+    Json(props): Json<Register>,
+) -> Result<(StatusCode, Json<AuthorisedUser>), RegisterError> {
+    props.validate()?;
+
+    let hash = hash_password(props.password).await?;
+    let result = ModelUser::create(&state.db, &props.username, &hash).await?;
+    let authorised_user = auth::issue(&state, result, props.device).await?;
+
+    Ok((StatusCode::CREATED, Json(authorised_user)))
 }
 
 #[debug_handler]
@@ -44,11 +162,169 @@ pub async fn login(
     State(state): State<Arc<AppState>>,
     Json(props): Json<Login>,
 ) -> Result<Json<AuthorisedUser>, LoginError> {
-    let result = ModelUser::get(&state.db, props).await?;
+    let result = match ModelUser::get_by_username(&state.db, &props.username).await {
+        Ok(result) => result,
+        // Answer as slowly as for a wrong password so usernames can't be probed by timing
+        Err(sqlx::Error::RowNotFound) => {
+            verify_dummy_password(props.password).await?;
+            return Err(LoginError::InvalidPassword);
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    match verify_password(props.password.clone(), result.password.clone()).await? {
+        Verification::Valid => (),
+        // Replace the plaintext password with a hash now that we know it
+        Verification::ValidLegacy => {
+            let hash = hash_password(props.password).await?;
+            ModelUser::update_password(&state.db, result.id, &hash).await?;
+        }
+        Verification::Invalid => return Err(LoginError::InvalidPassword),
+    }
+
+    Ok(Json(auth::issue(&state, result, props.device).await?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Refresh {
+    pub refresh_token: Uuid,
+}
+
+/* Rotates the refresh token, the old one stops working right away */
+#[debug_handler]
+pub async fn refresh(
+    State(state): State<Arc<AppState>>,
+    Json(props): Json<Refresh>,
+) -> Result<Json<AuthorisedUser>, AuthError> {
+    Ok(Json(auth::refresh(&state, props.refresh_token).await?))
+}
+
+#[derive(Serialize, Debug)]
+pub struct SessionInfo {
+    device: Option<String>,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    // Whether this is the session making the request
+    current: bool,
+}
+
+/* Lists active sessions of the user without exposing their tokens */
+#[debug_handler]
+pub async fn sessions(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Result<Json<Vec<SessionInfo>>, AuthError> {
+    let sessions = ModelSession::get_active_for_user(&state.db, auth.user.id)
+        .await?
+        .into_iter()
+        .map(|session| SessionInfo {
+            current: session.id == auth.session_id,
+            device: session.device,
+            created_at: session.created_at,
+            expires_at: session.expires_at,
+        })
+        .collect();
+
+    Ok(Json(sessions))
+}
+
+/* Revokes the session of the presented token */
+#[debug_handler]
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Result<StatusCode, AuthError> {
+    ModelSession::revoke(&state.db, auth.session_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/* Revokes every session of the user, logging them out on all devices */
+#[debug_handler]
+pub async fn logout_all(
+    State(state): State<Arc<AppState>>,
+    auth: AuthSession,
+) -> Result<StatusCode, AuthError> {
+    ModelSession::revoke_all(&state.db, auth.user.id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::TokenMode,
+        test_util::{create_user, test_state},
+    };
+
+    fn new_user(username: &str, password: &str) -> Json<Register> {
+        Json(Register {
+            username: username.to_string(),
+            password: password.to_string(),
+            device: None,
+        })
+    }
 
-    Ok(Json(AuthorisedUser {
-        id: result.id,
-        username: result.username,
-        token: result.token,
-    }))
+    #[tokio::test]
+    async fn register_validates_username_and_password() {
+        let (state, _db) = test_state(TokenMode::Session).await;
+
+        for (username, password) in [
+            ("al", "long enough"),
+            (&"a".repeat(33), "long enough"),
+            ("alice smith", "long enough"),
+            ("alice", "short"),
+            ("alice", &"p".repeat(129)),
+        ] {
+            let result = register(State(state.clone()), new_user(username, password)).await;
+            assert!(
+                matches!(result, Err(RegisterError::ValidationError(_))),
+                "{} / {}",
+                username,
+                password
+            );
+        }
+        assert!(ModelUser::get_by_username(&state.db, "alice")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn register_stores_a_hash_and_logs_in() {
+        let (state, _db) = test_state(TokenMode::Session).await;
+
+        let (status, Json(user)) =
+            register(State(state.clone()), new_user("al_ice.1", "password1"))
+                .await
+                .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(user.username, "al_ice.1");
+        assert!(auth::authenticate(&state, &user.token).await.is_ok());
+
+        let stored = ModelUser::get_by_username(&state.db, "al_ice.1")
+            .await
+            .unwrap();
+        assert_ne!(stored.password, "password1");
+        assert_eq!(
+            verify_password("password1".to_string(), stored.password)
+                .await
+                .unwrap(),
+            Verification::Valid
+        );
+    }
+
+    #[tokio::test]
+    async fn register_rejects_taken_usernames() {
+        let (state, _db) = test_state(TokenMode::Session).await;
+        create_user(&state.db, "alice").await;
+
+        let result = register(State(state.clone()), new_user("alice", "password2")).await;
+
+        assert!(matches!(result, Err(RegisterError::UsernameTaken)));
+        assert_eq!(
+            result.unwrap_err().into_response().status(),
+            StatusCode::CONFLICT
+        );
+    }
 }
@@ -1,31 +1,49 @@
 use axum::{
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
     serve, Router,
 };
+use chrono::{DateTime, Utc};
+use config::Config;
+use controller::Controller;
 use dotenv::dotenv;
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
 use std::sync::Arc;
-use tokio::{net::TcpListener, sync::broadcast};
+use tokio::net::TcpListener;
 use tower_http::cors::CorsLayer;
 use tracing::log::{set_max_level, LevelFilter};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use uuid::Uuid;
-use websocket::Controller;
 
-use crate::models::{HistoryMessage, ModelUser};
+use crate::models::{
+    ChatKind, ChatMessage, ChatRole, HistoryCursor, ModelChat, ModelInvite, ModelUser,
+    ReactionCount, UserChat,
+};
 
+mod api;
 mod app_error;
+mod auth;
+mod bus;
+mod config;
+mod controller;
 mod db;
 mod login;
 mod models;
+mod password;
+#[cfg(test)]
+mod test_util;
 mod websocket;
 
 #[derive(Eq, Hash, PartialEq, Serialize, Deserialize, Clone, Debug)]
 struct AuthorisedUser {
     id: Uuid,
     username: String,
-    token: Uuid,
+    // Access token, either a session token or a JWT depending on the token mode
+    token: String,
+    expires_at: DateTime<Utc>,
+    // Only issued in jwt mode, exchanged for new tokens at /token/refresh
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<Uuid>,
 }
 
 #[derive(Eq, Hash, PartialEq, Serialize, Deserialize, Clone, Debug)]
@@ -43,38 +61,369 @@ impl User {
     }
 }
 
+#[derive(Eq, Hash, PartialEq, Serialize, Deserialize, Clone, Debug)]
+struct Chat {
+    id: Uuid,
+    kind: ChatKind,
+    name: Option<String>,
+    topic: Option<String>,
+    avatar_url: Option<String>,
+    created_by: Option<Uuid>,
+    created_at: DateTime<Utc>,
+    archived: bool,
+    public: bool,
+    // Only filled in chat lists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unread_count: Option<i64>,
+}
+
+impl Chat {
+    fn from_model_chat(chat: ModelChat) -> Chat {
+        Chat {
+            id: chat.id,
+            kind: chat.kind,
+            name: chat.name,
+            topic: chat.topic,
+            avatar_url: chat.avatar_url,
+            created_by: chat.created_by,
+            created_at: chat.created_at,
+            archived: chat.archived,
+            public: chat.public,
+            unread_count: None,
+        }
+    }
+
+    fn from_user_chat(chat: UserChat) -> Chat {
+        Chat {
+            id: chat.id,
+            kind: chat.kind,
+            name: chat.name,
+            topic: chat.topic,
+            avatar_url: chat.avatar_url,
+            created_by: chat.created_by,
+            created_at: chat.created_at,
+            archived: chat.archived,
+            public: chat.public,
+            unread_count: Some(chat.unread_count),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Invite {
+    code: String,
+    chat_id: Uuid,
+    created_by: Uuid,
+    // Set for invites only this user can accept
+    user_id: Option<Uuid>,
+    expires_at: Option<DateTime<Utc>>,
+    max_uses: Option<i32>,
+    uses: i32,
+    created_at: DateTime<Utc>,
+}
+
+impl Invite {
+    fn from_model_invite(invite: ModelInvite) -> Invite {
+        Invite {
+            code: invite.code,
+            chat_id: invite.chat_id,
+            created_by: invite.created_by,
+            user_id: invite.user_id,
+            expires_at: invite.expires_at,
+            max_uses: invite.max_uses,
+            uses: invite.uses,
+            created_at: invite.created_at,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
 enum RequestMessage {
-    Join { token: Uuid },
-    Message { content: String },
+    Join {
+        token: String,
+        // Chat to enter right away, the client can pick one later with `EnterChat`
+        chat_id: Option<Uuid>,
+    },
+    Message {
+        content: String,
+        // Lets the client retry without creating duplicates
+        client_msg_id: Option<String>,
+        reply_to: Option<Uuid>,
+    },
+    // A group chat unless `kind` says otherwise
+    CreateChat {
+        kind: Option<ChatKind>,
+    },
+    // Finds or creates the direct chat with another user
+    OpenDirect {
+        user_id: Uuid,
+    },
+    ListChats,
+    // Membership, a chat has to be joined before it can be entered
+    JoinChat {
+        chat_id: Uuid,
+    },
+    LeaveChat {
+        chat_id: Uuid,
+    },
+    EnterChat {
+        chat_id: Uuid,
+    },
+    RemoveMember {
+        chat_id: Uuid,
+        user_id: Uuid,
+    },
+    SetRole {
+        chat_id: Uuid,
+        user_id: Uuid,
+        role: ChatRole,
+    },
+    // Fields left out stay as they are, an empty string clears a field
+    UpdateChat {
+        chat_id: Uuid,
+        name: Option<String>,
+        topic: Option<String>,
+        avatar_url: Option<String>,
+        archived: Option<bool>,
+        public: Option<bool>,
+    },
+    // Older messages of the current chat, `before` is the oldest message the client has
+    LoadHistory {
+        before: Option<HistoryCursor>,
+        limit: Option<i64>,
+    },
+    EditMessage {
+        message_id: Uuid,
+        content: String,
+    },
+    DeleteMessage {
+        message_id: Uuid,
+    },
+    // All replies to a message of the current chat
+    LoadThread {
+        message_id: Uuid,
+    },
+    Typing {
+        chat_id: Uuid,
+    },
+    // Everything up to and including `message_id` has been seen
+    MarkRead {
+        chat_id: Uuid,
+        message_id: Uuid,
+    },
+    React {
+        message_id: Uuid,
+        emoji: String,
+    },
+    Unreact {
+        message_id: Uuid,
+        emoji: String,
+    },
+    PinMessage {
+        message_id: Uuid,
+    },
+    UnpinMessage {
+        message_id: Uuid,
+    },
+}
+
+/* Stable error codes clients can match on */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    InvalidToken,
+    TokenExpired,
+    TokenRevoked,
+    HandshakeTimeout,
+    MalformedJson,
+    InvalidMessage,
+    ChatNotFound,
+    NotInChat,
+    NotMember,
+    ChatArchived,
+    MessageNotFound,
+    UserNotFound,
+    InviteNotFound,
+    InviteExpired,
+    Forbidden,
+    ServerError,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type")]
 enum ResponseMessage {
+    // Presence, sent for the first and the last connection of a member
     Join {
         user: User,
     },
     Leave {
         user: User,
     },
-    Message {
-        username: String,
+    MemberJoined {
+        chat_id: Uuid,
+        user: User,
+    },
+    MemberLeft {
+        chat_id: Uuid,
+        user: User,
+    },
+    RoleChanged {
+        chat_id: Uuid,
+        user: User,
+        role: ChatRole,
+    },
+    Message(ChatMessage),
+    // The connection fell behind and dropped events, the client should reload the chat
+    Resync {
+        chat_id: Uuid,
+        missed: u64,
+    },
+    // Sent to the author once their message is stored
+    Ack {
+        client_msg_id: Option<String>,
+        message_id: Uuid,
+        timestamp: DateTime<Utc>,
+    },
+    MessageEdited {
+        chat_id: Uuid,
+        message_id: Uuid,
         content: String,
+        edited_at: DateTime<Utc>,
+    },
+    MessageDeleted {
+        chat_id: Uuid,
+        message_id: Uuid,
+        deleted_at: DateTime<Utc>,
+    },
+    // Not sent back to the typing user
+    Typing {
+        chat_id: Uuid,
+        user: User,
+    },
+    StoppedTyping {
+        chat_id: Uuid,
+        user: User,
+    },
+    ReadReceipt {
+        chat_id: Uuid,
+        user: User,
+        message_id: Uuid,
+        read_at: DateTime<Utc>,
+    },
+    ReactionUpdated {
+        chat_id: Uuid,
+        message_id: Uuid,
+        reactions: Vec<ReactionCount>,
+    },
+    MessagePinned {
+        chat_id: Uuid,
+        message_id: Uuid,
+        pinned_by: User,
+        pinned_at: DateTime<Utc>,
+    },
+    MessageUnpinned {
+        chat_id: Uuid,
+        message_id: Uuid,
     },
     History {
-        messages: Vec<HistoryMessage>,
+        chat_id: Uuid,
+        messages: Vec<ChatMessage>,
+        has_more: bool,
         users: Vec<User>,
     },
+    HistoryPage {
+        chat_id: Uuid,
+        messages: Vec<ChatMessage>,
+        has_more: bool,
+    },
+    Thread {
+        chat_id: Uuid,
+        message_id: Uuid,
+        messages: Vec<ChatMessage>,
+    },
+    ChatCreated {
+        chat: Chat,
+    },
+    ChatUpdated {
+        chat: Chat,
+    },
+    DirectOpened {
+        chat: Chat,
+    },
+    Chats {
+        chats: Vec<Chat>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+        // Echoes `request_id` of the request that failed, if the client sent one
+        request_id: Option<String>,
+    },
 }
 
 pub struct AppState {
-    broadcast_sender: broadcast::Sender<ResponseMessage>,
+    config: Config,
     db: Pool<Postgres>,
     controller: Controller,
 }
 
+fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/login", post(login::login))
+        .route("/register", post(login::register))
+        .route("/sessions", get(login::sessions))
+        .route("/token/refresh", post(login::refresh))
+        .route("/logout", post(login::logout))
+        .route("/logout/all", post(login::logout_all))
+        .route("/chats", get(api::list_chats).post(api::create_chat))
+        .route(
+            "/chats/:chat_id/messages",
+            get(api::list_messages).post(api::send_message),
+        )
+        .route(
+            "/chats/:chat_id/messages/:message_id",
+            patch(api::edit_message).delete(api::delete_message),
+        )
+        .route(
+            "/chats/:chat_id/messages/:message_id/replies",
+            get(api::list_replies),
+        )
+        .route(
+            "/chats/:chat_id/messages/:message_id/reactions/:emoji",
+            put(api::react).delete(api::unreact),
+        )
+        .route("/chats/direct", post(api::open_direct))
+        .route(
+            "/chats/:chat_id",
+            get(api::get_chat).patch(api::update_chat),
+        )
+        .route(
+            "/chats/:chat_id/messages/:message_id/pin",
+            put(api::pin_message).delete(api::unpin_message),
+        )
+        .route("/chats/:chat_id/pins", get(api::list_pins))
+        .route("/chats/:chat_id/members", get(api::list_members))
+        .route(
+            "/chats/:chat_id/members/:user_id",
+            delete(api::remove_member),
+        )
+        .route("/chats/:chat_id/members/:user_id/role", put(api::set_role))
+        .route("/chats/:chat_id/join", post(api::join_chat))
+        .route("/chats/:chat_id/leave", post(api::leave_chat))
+        .route("/chats/:chat_id/read", post(api::mark_read))
+        .route(
+            "/chats/:chat_id/invites",
+            get(api::list_invites).post(api::create_invite),
+        )
+        .route("/chats/:chat_id/invites/:code", delete(api::revoke_invite))
+        .route("/invites", get(api::list_user_invites))
+        .route("/invites/:code/accept", post(api::accept_invite))
+        .route("/websocket", get(websocket::websocket_handler))
+        .with_state(state)
+        .layer(CorsLayer::permissive())
+}
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
@@ -89,26 +438,26 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer().compact().pretty())
         .init();
 
-    let (broadcast_sender, _broadcast_receiver) = broadcast::channel(100);
+    let config = Config::from_env();
+    let controller = Controller::new(pool.clone(), &config);
+    // Connections of a previous run of this instance are gone
+    controller
+        .clear_presence()
+        .await
+        .expect("Failed to clear stale presence");
 
+    let port = config.port;
     let app_state = Arc::new(AppState {
-        broadcast_sender: broadcast_sender.clone(),
+        config,
         db: pool.clone(),
-        controller: Controller::new(pool.clone(), broadcast_sender.clone()),
+        controller,
     });
 
-    let app = Router::new()
-        .route("/login", post(login::login))
-        .route("/websocket", get(websocket::websocket_handler))
-        .with_state(app_state)
-        .layer(CorsLayer::permissive());
+    let app = router(app_state);
 
-    let listener = TcpListener::bind("127.0.0.1:3001").await.unwrap();
+    let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
     serve(listener, app).await.unwrap();
 }
 
 // 5. Add elasticsearch over messages
-// 6. Add Several chats for user
-// 7. Add tests
-// 8. User creation
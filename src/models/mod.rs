@@ -9,3 +9,18 @@ pub use self::model_chat::*;
 
 mod model_message;
 pub use self::model_message::*;
+
+mod model_session;
+pub use self::model_session::*;
+
+mod model_reaction;
+pub use self::model_reaction::*;
+
+mod model_presence;
+pub use self::model_presence::*;
+
+mod model_bus_event;
+pub use self::model_bus_event::*;
+
+mod model_invite;
+pub use self::model_invite::*;
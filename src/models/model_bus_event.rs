@@ -0,0 +1,44 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use super::DatabaseResult;
+
+/* Event payloads handed between server instances through Postgres */
+pub struct ModelBusEvent;
+
+impl ModelBusEvent {
+    pub async fn notify(pool: &PgPool, channel: &str, payload: &str) -> DatabaseResult<()> {
+        sqlx::query!("SELECT pg_notify($1, $2)", channel, payload)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn create(pool: &PgPool, payload: &str) -> DatabaseResult<i64> {
+        let row = sqlx::query!(
+            "INSERT INTO bus_events (payload) VALUES ($1) RETURNING id",
+            payload
+        )
+        .fetch_one(pool)
+        .await?;
+
+        Ok(row.id)
+    }
+
+    pub async fn get(pool: &PgPool, id: i64) -> DatabaseResult<String> {
+        let row = sqlx::query!("SELECT payload FROM bus_events WHERE id = $1", id)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(row.payload)
+    }
+
+    pub async fn delete_older_than(pool: &PgPool, before: DateTime<Utc>) -> DatabaseResult<()> {
+        sqlx::query!("DELETE FROM bus_events WHERE created_at < $1", before)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+}
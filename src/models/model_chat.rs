@@ -1,46 +1,207 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use uuid::Uuid;
 
-use super::{DatabaseResult, PostgresResult};
+use super::DatabaseResult;
+
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ChatKind {
+    Group,
+    Direct,
+    // Joins as read-only, only admins and members they promote post
+    Announcement,
+}
+
+impl ChatKind {
+    /* Role of users joining the chat, direct chats can't be joined */
+    pub fn joining_role(self) -> Option<ChatRole> {
+        match self {
+            ChatKind::Group => Some(ChatRole::Member),
+            ChatKind::Announcement => Some(ChatRole::ReadOnly),
+            ChatKind::Direct => None,
+        }
+    }
+}
+
+/* Ordered from least to most privileged */
+#[derive(
+    sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ChatRole {
+    ReadOnly,
+    Member,
+    Admin,
+    Owner,
+}
+
+impl ChatRole {
+    pub fn can_post(self) -> bool {
+        self >= ChatRole::Member
+    }
+
+    pub fn can_invite(self) -> bool {
+        self >= ChatRole::Admin
+    }
+
+    /* Changing the chat, removing members, pinning and deleting messages of others */
+    pub fn can_moderate(self) -> bool {
+        self >= ChatRole::Admin
+    }
+}
 
 pub struct ModelChat {
     pub id: Uuid,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub avatar_url: Option<String>,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub archived: bool,
+    // Joined without an invite
+    pub public: bool,
+    pub kind: ChatKind,
+}
+
+/* Fields left out stay as they are, an empty string clears a field */
+#[derive(Deserialize, Debug, Default)]
+pub struct ChatChanges {
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub avatar_url: Option<String>,
+    pub archived: Option<bool>,
+    pub public: Option<bool>,
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum ChatError {
     #[error("Chat not found")]
     ChatNotFound,
+    #[error(transparent)]
+    DatabaseError(sqlx::Error),
 }
 
-impl From<uuid::Error> for ChatError {
-    fn from(_: uuid::Error) -> Self {
-        ChatError::ChatNotFound
+impl From<sqlx::Error> for ChatError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => ChatError::ChatNotFound,
+            e => ChatError::DatabaseError(e),
+        }
     }
 }
 
 impl ModelChat {
-    pub async fn new(pool: &PgPool) -> PostgresResult<Self> {
+    pub async fn new(pool: &PgPool, created_by: Uuid, kind: ChatKind) -> DatabaseResult<Self> {
+        let new_uuid = Uuid::new_v4();
+        sqlx::query_as!(
+            ModelChat,
+            r#"INSERT INTO chats (id, created_by, kind) VALUES ($1, $2, $3)
+            RETURNING id, name, topic, avatar_url, created_by, created_at, archived, public, kind as "kind: ChatKind""#,
+            new_uuid,
+            created_by,
+            kind as ChatKind
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    /* The direct chat of two users, created on first use. Has no creator so neither side administers it */
+    /* Whether the chat was just created comes along with it, a new chat already has both users as members */
+    pub async fn get_or_create_direct(
+        pool: &PgPool,
+        a: Uuid,
+        b: Uuid,
+    ) -> DatabaseResult<(Self, bool)> {
+        let (first, second) = if a < b { (a, b) } else { (b, a) };
+        let direct_key = format!("{}:{}", first, second);
+
+        let mut tx = pool.begin().await?;
         let new_uuid = Uuid::new_v4();
+        // Waits for a concurrent insert of the same chat to commit or roll back
+        let created = sqlx::query_as!(
+            ModelChat,
+            r#"INSERT INTO chats (id, kind, direct_key) VALUES ($1, 'direct', $2)
+            ON CONFLICT (direct_key) DO NOTHING
+            RETURNING id, name, topic, avatar_url, created_by, created_at, archived, public, kind as "kind: ChatKind""#,
+            new_uuid,
+            direct_key
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let result = match created {
+            Some(chat) => {
+                sqlx::query!(
+                    "INSERT INTO chat_user (chat_id, user_id, role) VALUES ($1, $2, $4), ($1, $3, $4)",
+                    chat.id,
+                    first,
+                    second,
+                    ChatRole::Member as ChatRole
+                )
+                .execute(&mut *tx)
+                .await?;
+                (chat, true)
+            }
+            None => {
+                let chat = sqlx::query_as!(
+                    ModelChat,
+                    r#"SELECT id, name, topic, avatar_url, created_by, created_at, archived, public, kind as "kind: ChatKind" FROM chats WHERE direct_key = $1"#,
+                    direct_key
+                )
+                .fetch_one(&mut *tx)
+                .await?;
+                (chat, false)
+            }
+        };
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn update(pool: &PgPool, id: Uuid, changes: &ChatChanges) -> Result<Self, ChatError> {
         Ok(sqlx::query_as!(
             ModelChat,
-            "INSERT INTO chats (id) VALUES ($1) RETURNING *",
-            new_uuid
+            r#"UPDATE chats SET
+                name = CASE WHEN $2::VARCHAR IS NULL THEN name ELSE NULLIF($2, '') END,
+                topic = CASE WHEN $3::VARCHAR IS NULL THEN topic ELSE NULLIF($3, '') END,
+                avatar_url = CASE WHEN $4::VARCHAR IS NULL THEN avatar_url ELSE NULLIF($4, '') END,
+                archived = COALESCE($5, archived),
+                public = COALESCE($6, public)
+            WHERE id = $1
+            RETURNING id, name, topic, avatar_url, created_by, created_at, archived, public, kind as "kind: ChatKind""#,
+            id,
+            changes.name,
+            changes.topic,
+            changes.avatar_url,
+            changes.archived,
+            changes.public
         )
         .fetch_one(pool)
         .await?)
     }
 
-    pub fn get_id() -> Result<Uuid, ChatError> {
-        // 1. Handle chart creation
-        // 2. Handle getting chat id
-        Ok(Uuid::parse_str("d58535ec-fe54-4d30-9808-94af7d6dc1bf")?)
+    pub async fn get(pool: &PgPool, id: Uuid) -> Result<Self, ChatError> {
+        Ok(sqlx::query_as!(
+            ModelChat,
+            r#"SELECT id, name, topic, avatar_url, created_by, created_at, archived, public, kind as "kind: ChatKind" FROM chats WHERE id = $1"#,
+            id
+        )
+        .fetch_one(pool)
+        .await?)
     }
 }
 
+#[allow(dead_code)]
 pub struct ModelChatUser {
     pub chat_id: Uuid,
     pub user_id: Uuid,
+    pub last_read_message_id: Option<Uuid>,
+    pub last_read_at: Option<DateTime<Utc>>,
+    pub role: ChatRole,
 }
 
 impl Default for ModelChatUser {
@@ -48,31 +209,258 @@ impl Default for ModelChatUser {
         ModelChatUser {
             chat_id: Uuid::nil(),
             user_id: Uuid::nil(),
+            last_read_message_id: None,
+            last_read_at: None,
+            role: ChatRole::Member,
         }
     }
 }
 
+/* A member of a chat with their role */
+#[derive(Serialize, Debug)]
+pub struct ChatMember {
+    pub id: Uuid,
+    pub username: String,
+    pub role: ChatRole,
+}
+
+/* A chat of a user with the messages of others they have not read yet */
+pub struct UserChat {
+    pub id: Uuid,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub avatar_url: Option<String>,
+    pub created_by: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub archived: bool,
+    pub public: bool,
+    pub kind: ChatKind,
+    pub unread_count: i64,
+}
+
+pub enum Leaving {
+    Left,
+    NotMember,
+    // The owner has to hand the chat over first
+    OwnerWithMembers,
+}
+
 impl ModelChatUser {
-    pub async fn create(pool: &PgPool, chat_id: Uuid, user_id: Uuid) -> DatabaseResult<Self> {
-        sqlx::query_as!(
-            ModelChatUser,
-            "INSERT INTO chat_user (chat_id, user_id) VALUES ($1, $2) RETURNING *",
+    /* Returns false if the user already is a member, their role stays as it is then, or the chat is gone */
+    pub async fn create(
+        pool: &PgPool,
+        chat_id: Uuid,
+        user_id: Uuid,
+        role: ChatRole,
+    ) -> DatabaseResult<bool> {
+        let result = sqlx::query!(
+            "INSERT INTO chat_user (chat_id, user_id, role)
+            SELECT id, $2, $3 FROM chats WHERE id = $1 FOR SHARE
+            ON CONFLICT DO NOTHING",
+            chat_id,
+            user_id,
+            role as ChatRole
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /* Returns false if the user was not a member */
+    pub async fn delete(pool: &PgPool, chat_id: Uuid, user_id: Uuid) -> DatabaseResult<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM chat_user WHERE chat_id = $1 AND user_id = $2",
             chat_id,
             user_id
         )
-        .fetch_one(pool)
-        .await
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn exists(pool: &PgPool, chat_id: Uuid, user_id: Uuid) -> DatabaseResult<bool> {
+        let row = sqlx::query!(
+            "SELECT 1 as exists FROM chat_user WHERE chat_id = $1 AND user_id = $2",
+            chat_id,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.is_some())
+    }
+
+    /* None if the user is not a member */
+    pub async fn get_role(
+        pool: &PgPool,
+        chat_id: Uuid,
+        user_id: Uuid,
+    ) -> DatabaseResult<Option<ChatRole>> {
+        let row = sqlx::query!(
+            r#"SELECT role as "role: ChatRole" FROM chat_user WHERE chat_id = $1 AND user_id = $2"#,
+            chat_id,
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(|row| row.role))
+    }
+
+    /* Returns false if the user is not a member */
+    pub async fn set_role(
+        pool: &PgPool,
+        chat_id: Uuid,
+        user_id: Uuid,
+        role: ChatRole,
+    ) -> DatabaseResult<bool> {
+        let result = sqlx::query!(
+            "UPDATE chat_user SET role = $3 WHERE chat_id = $1 AND user_id = $2",
+            chat_id,
+            user_id,
+            role as ChatRole
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /* Promotes the member and demotes the owner together, false if either isn't one anymore */
+    pub async fn transfer_ownership(
+        pool: &PgPool,
+        chat_id: Uuid,
+        owner_id: Uuid,
+        member_id: Uuid,
+    ) -> DatabaseResult<bool> {
+        let mut tx = pool.begin().await?;
+
+        let demoted = sqlx::query!(
+            "UPDATE chat_user SET role = $3 WHERE chat_id = $1 AND user_id = $2 AND role = $4",
+            chat_id,
+            owner_id,
+            ChatRole::Admin as ChatRole,
+            ChatRole::Owner as ChatRole
+        )
+        .execute(&mut *tx)
+        .await?;
+        let promoted = sqlx::query!(
+            "UPDATE chat_user SET role = $3 WHERE chat_id = $1 AND user_id = $2",
+            chat_id,
+            member_id,
+            ChatRole::Owner as ChatRole
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        if demoted.rows_affected() == 0 || promoted.rows_affected() == 0 {
+            tx.rollback().await?;
+            return Ok(false);
+        }
+        tx.commit().await?;
+
+        Ok(true)
     }
 
-    pub async fn delete(pool: &PgPool, chat_id: Uuid, user_id: Uuid) -> DatabaseResult<()> {
+    /* Owners only leave once they are alone, the chat row stays locked so nobody joins in between */
+    pub async fn leave(pool: &PgPool, chat_id: Uuid, user_id: Uuid) -> DatabaseResult<Leaving> {
+        let mut tx = pool.begin().await?;
+
+        // Adding a member share locks the chat row, so it waits for this
         sqlx::query!(
-            "DELETE FROM chat_user WHERE chat_id = $1 AND user_id = $2",
+            "SELECT 1 as locked FROM chats WHERE id = $1 FOR UPDATE",
+            chat_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let row = sqlx::query!(
+            r#"SELECT role as "role: ChatRole",
+                EXISTS (SELECT 1 FROM chat_user others WHERE others.chat_id = $1 AND others.user_id <> $2) as "others!"
+            FROM chat_user WHERE chat_id = $1 AND user_id = $2"#,
             chat_id,
             user_id
         )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let leaving = match row {
+            None => Leaving::NotMember,
+            Some(row) if row.role == ChatRole::Owner && row.others => Leaving::OwnerWithMembers,
+            Some(_) => {
+                sqlx::query!(
+                    "DELETE FROM chat_user WHERE chat_id = $1 AND user_id = $2",
+                    chat_id,
+                    user_id
+                )
+                .execute(&mut *tx)
+                .await?;
+                Leaving::Left
+            }
+        };
+        tx.commit().await?;
+
+        Ok(leaving)
+    }
+
+    pub async fn get_members(pool: &PgPool, chat_id: Uuid) -> DatabaseResult<Vec<ChatMember>> {
+        sqlx::query_as!(
+            ChatMember,
+            r#"SELECT users.id, users.username, chat_user.role as "role: ChatRole" FROM chat_user
+            INNER JOIN users ON users.id = chat_user.user_id
+            WHERE chat_user.chat_id = $1
+            ORDER BY users.username"#,
+            chat_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /* Only moves forward, returns false if `message_id` is not newer than the last read one */
+    pub async fn mark_read(
+        pool: &PgPool,
+        chat_id: Uuid,
+        user_id: Uuid,
+        message_id: Uuid,
+        read_at: DateTime<Utc>,
+    ) -> DatabaseResult<bool> {
+        let result = sqlx::query!(
+            "UPDATE chat_user SET last_read_message_id = $3, last_read_at = $4
+            WHERE chat_id = $1 AND user_id = $2
+                AND (last_read_message_id IS NULL
+                    OR (SELECT (created_at, id) FROM messages WHERE id = last_read_message_id)
+                        < (SELECT (created_at, id) FROM messages WHERE id = $3))",
+            chat_id,
+            user_id,
+            message_id,
+            read_at
+        )
         .execute(pool)
         .await?;
 
-        Ok(())
+        Ok(result.rows_affected() > 0)
+    }
+
+    /* Chats of a user with the number of messages after their last read one */
+    pub async fn get_user_chats(pool: &PgPool, user_id: Uuid) -> DatabaseResult<Vec<UserChat>> {
+        sqlx::query_as!(
+            UserChat,
+            r#"SELECT chats.id, chats.name, chats.topic, chats.avatar_url, chats.created_by,
+                chats.created_at, chats.archived, chats.public, chats.kind as "kind: ChatKind",
+                COUNT(messages.id) as "unread_count!"
+            FROM chat_user
+            JOIN chats ON chats.id = chat_user.chat_id
+            LEFT JOIN messages last_read ON last_read.id = chat_user.last_read_message_id
+            LEFT JOIN messages ON messages.chat_id = chat_user.chat_id
+                AND messages.user_id <> chat_user.user_id
+                AND messages.deleted_at IS NULL
+                AND (last_read.id IS NULL OR (messages.created_at, messages.id) > (last_read.created_at, last_read.id))
+            WHERE chat_user.user_id = $1
+            GROUP BY chats.id"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await
     }
 }
@@ -0,0 +1,157 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{ChatRole, DatabaseResult};
+
+/* Code that lets users join a chat, pending until it expires or is used up */
+pub struct ModelInvite {
+    pub code: String,
+    pub chat_id: Uuid,
+    pub created_by: Uuid,
+    pub user_id: Option<Uuid>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+pub enum InviteAcceptance {
+    Joined,
+    // Uses of members are not counted
+    AlreadyMember,
+    Expired,
+}
+
+impl ModelInvite {
+    pub async fn create(
+        pool: &PgPool,
+        chat_id: Uuid,
+        created_by: Uuid,
+        user_id: Option<Uuid>,
+        expires_at: Option<DateTime<Utc>>,
+        max_uses: Option<i32>,
+    ) -> DatabaseResult<ModelInvite> {
+        let code = Uuid::new_v4().simple().to_string();
+        sqlx::query_as!(
+            ModelInvite,
+            "INSERT INTO chat_invites (code, chat_id, created_by, user_id, expires_at, max_uses)
+            VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+            code,
+            chat_id,
+            created_by,
+            user_id,
+            expires_at,
+            max_uses
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    pub async fn get(pool: &PgPool, code: &str) -> DatabaseResult<ModelInvite> {
+        sqlx::query_as!(
+            ModelInvite,
+            "SELECT * FROM chat_invites WHERE code = $1",
+            code
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    /* Adds the member and counts the use together, so neither happens without the other */
+    pub async fn accept(
+        pool: &PgPool,
+        code: &str,
+        chat_id: Uuid,
+        user_id: Uuid,
+        role: ChatRole,
+        now: DateTime<Utc>,
+    ) -> DatabaseResult<InviteAcceptance> {
+        let mut tx = pool.begin().await?;
+
+        let joined = sqlx::query!(
+            "INSERT INTO chat_user (chat_id, user_id, role)
+            SELECT id, $2, $3 FROM chats WHERE id = $1 FOR SHARE
+            ON CONFLICT DO NOTHING",
+            chat_id,
+            user_id,
+            role as ChatRole
+        )
+        .execute(&mut *tx)
+        .await?;
+        if joined.rows_affected() == 0 {
+            tx.rollback().await?;
+            return Ok(InviteAcceptance::AlreadyMember);
+        }
+
+        let used = sqlx::query!(
+            "UPDATE chat_invites SET uses = uses + 1
+            WHERE code = $1
+                AND (expires_at IS NULL OR expires_at > $2)
+                AND (max_uses IS NULL OR uses < max_uses)",
+            code,
+            now
+        )
+        .execute(&mut *tx)
+        .await?;
+        if used.rows_affected() == 0 {
+            tx.rollback().await?;
+            return Ok(InviteAcceptance::Expired);
+        }
+        tx.commit().await?;
+
+        Ok(InviteAcceptance::Joined)
+    }
+
+    /* Returns false if there is no such invite for the chat */
+    pub async fn delete(pool: &PgPool, chat_id: Uuid, code: &str) -> DatabaseResult<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM chat_invites WHERE chat_id = $1 AND code = $2",
+            chat_id,
+            code
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn get_pending_for_chat(
+        pool: &PgPool,
+        chat_id: Uuid,
+        now: DateTime<Utc>,
+    ) -> DatabaseResult<Vec<ModelInvite>> {
+        sqlx::query_as!(
+            ModelInvite,
+            "SELECT * FROM chat_invites
+            WHERE chat_id = $1
+                AND (expires_at IS NULL OR expires_at > $2)
+                AND (max_uses IS NULL OR uses < max_uses)
+            ORDER BY created_at DESC",
+            chat_id,
+            now
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /* Invites addressed to the user, codes shared with everyone are not included */
+    pub async fn get_pending_for_user(
+        pool: &PgPool,
+        user_id: Uuid,
+        now: DateTime<Utc>,
+    ) -> DatabaseResult<Vec<ModelInvite>> {
+        sqlx::query_as!(
+            ModelInvite,
+            "SELECT * FROM chat_invites
+            WHERE user_id = $1
+                AND (expires_at IS NULL OR expires_at > $2)
+                AND (max_uses IS NULL OR uses < max_uses)
+            ORDER BY created_at DESC",
+            user_id,
+            now
+        )
+        .fetch_all(pool)
+        .await
+    }
+}
@@ -1,39 +1,126 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{types::Json, PgPool};
 use uuid::Uuid;
 
-use super::DatabaseResult;
+use super::{DatabaseResult, ReactionCount};
 
-/* History message structure of a Response event */
+// Characters of a replied message included with the reply
+const QUOTE_LENGTH: i32 = 100;
+
+/* Message structure of History and live Message events */
 // TODO: Doesn't belong to Model
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct HistoryMessage {
+pub struct ChatMessage {
+    id: Uuid,
+    chat_id: Uuid,
     user_id: Uuid,
     username: String,
     content: String,
     #[serde(rename = "timestamp")]
     created_at: DateTime<Utc>,
+    client_msg_id: Option<String>,
+    edited_at: Option<DateTime<Utc>>,
+    // Content of a deleted message is empty
+    deleted_at: Option<DateTime<Utc>>,
+    reply_to: Option<Uuid>,
+    // Author and beginning of the `reply_to` message
+    quoted_username: Option<String>,
+    quoted_content: Option<String>,
+    reactions: Json<Vec<ReactionCount>>,
+    pinned_at: Option<DateTime<Utc>>,
+    pinned_by: Option<Uuid>,
 }
 
-impl Default for HistoryMessage {
+impl Default for ChatMessage {
     fn default() -> Self {
-        HistoryMessage {
+        ChatMessage {
+            id: Uuid::nil(),
+            chat_id: Uuid::nil(),
             user_id: Uuid::nil(),
             username: String::from(""),
             content: String::from(""),
             created_at: Utc::now(),
+            client_msg_id: None,
+            edited_at: None,
+            deleted_at: None,
+            reply_to: None,
+            quoted_username: None,
+            quoted_content: None,
+            reactions: Json(Vec::new()),
+            pinned_at: None,
+            pinned_by: None,
+        }
+    }
+}
+
+impl ChatMessage {
+    pub fn from_model_message(message: ModelMessage, username: String) -> ChatMessage {
+        ChatMessage {
+            id: message.id,
+            chat_id: message.chat_id,
+            user_id: message.user_id,
+            username,
+            content: message.content,
+            created_at: message.created_at,
+            client_msg_id: message.client_msg_id,
+            edited_at: message.edited_at,
+            deleted_at: message.deleted_at,
+            reply_to: message.reply_to,
+            quoted_username: None,
+            quoted_content: None,
+            reactions: Json(Vec::new()),
+            pinned_at: message.pinned_at,
+            pinned_by: message.pinned_by,
+        }
+    }
+
+    pub fn quoting(self, quoted: ModelMessage, username: String) -> ChatMessage {
+        ChatMessage {
+            quoted_username: Some(username),
+            quoted_content: Some(quoted.content.chars().take(QUOTE_LENGTH as usize).collect()),
+            ..self
         }
     }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+}
+
+/* Position in a chat history, pages are loaded backwards from it */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryCursor {
+    pub id: Uuid,
+    #[serde(rename = "timestamp")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct HistoryPage {
+    // Oldest message first
+    pub messages: Vec<ChatMessage>,
+    // Whether there are older messages before this page
+    pub has_more: bool,
 }
 
 /* Message structure in a Messages table */
 pub struct ModelMessage {
-    id: Uuid,
-    chat_id: Uuid,
+    pub id: Uuid,
+    pub chat_id: Uuid,
     pub user_id: Uuid,
     pub content: String,
     pub created_at: DateTime<Utc>,
+    pub client_msg_id: Option<String>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub reply_to: Option<Uuid>,
+    pub pinned_at: Option<DateTime<Utc>>,
+    pub pinned_by: Option<Uuid>,
 }
 
 impl Default for ModelMessage {
@@ -44,41 +131,384 @@ impl Default for ModelMessage {
             user_id: Uuid::nil(),
             content: String::from(""),
             created_at: Utc::now(),
+            client_msg_id: None,
+            edited_at: None,
+            deleted_at: None,
+            reply_to: None,
+            pinned_at: None,
+            pinned_by: None,
         }
     }
 }
 
 impl ModelMessage {
+    /* Returns None if the user already sent a message with the same client_msg_id to the chat */
     pub async fn create(
         pool: &PgPool,
         chat_id: Uuid,
         user_id: Uuid,
         content: String,
         created_at: DateTime<Utc>,
-    ) -> DatabaseResult<ModelMessage> {
+        client_msg_id: Option<String>,
+        reply_to: Option<Uuid>,
+    ) -> DatabaseResult<Option<ModelMessage>> {
         sqlx::query_as!(
             ModelMessage,
-            "INSERT INTO messages (id, chat_id, user_id, content, created_at) VALUES (gen_random_uuid(), $1, $2, $3, $4) RETURNING *",
+            "INSERT INTO messages (id, chat_id, user_id, content, created_at, client_msg_id, reply_to) VALUES (gen_random_uuid(), $1, $2, $3, $4, $5, $6)
+            ON CONFLICT (chat_id, user_id, client_msg_id) DO NOTHING RETURNING *",
             chat_id,
             user_id,
             content,
-            created_at
-        ).fetch_one(pool)
+            created_at,
+            client_msg_id,
+            reply_to
+        ).fetch_optional(pool)
+        .await
+    }
+
+    pub async fn get(pool: &PgPool, chat_id: Uuid, id: Uuid) -> DatabaseResult<ModelMessage> {
+        sqlx::query_as!(
+            ModelMessage,
+            "SELECT * FROM messages WHERE id = $1 AND chat_id = $2",
+            id,
+            chat_id
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    /* Fails with RowNotFound if the message was deleted in the meantime */
+    pub async fn update_content(
+        pool: &PgPool,
+        id: Uuid,
+        content: &str,
+        edited_at: DateTime<Utc>,
+    ) -> DatabaseResult<()> {
+        let result = sqlx::query!(
+            "UPDATE messages SET content = $2, edited_at = $3 WHERE id = $1 AND deleted_at IS NULL",
+            id,
+            content,
+            edited_at
+        )
+        .execute(pool)
+        .await?;
+
+        match result.rows_affected() {
+            0 => Err(sqlx::Error::RowNotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /* Keeps the row as a tombstone so history pages and cursors stay stable */
+    pub async fn soft_delete(
+        pool: &PgPool,
+        id: Uuid,
+        deleted_at: DateTime<Utc>,
+    ) -> DatabaseResult<()> {
+        let result = sqlx::query!(
+            "UPDATE messages SET content = '', deleted_at = $2, pinned_at = NULL, pinned_by = NULL WHERE id = $1 AND deleted_at IS NULL",
+            id,
+            deleted_at
+        )
+        .execute(pool)
+        .await?;
+
+        match result.rows_affected() {
+            0 => Err(sqlx::Error::RowNotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /* Returns false if the message is already pinned */
+    pub async fn pin(
+        pool: &PgPool,
+        id: Uuid,
+        pinned_by: Uuid,
+        pinned_at: DateTime<Utc>,
+    ) -> DatabaseResult<bool> {
+        let result = sqlx::query!(
+            "UPDATE messages SET pinned_at = $3, pinned_by = $2
+            WHERE id = $1 AND deleted_at IS NULL AND pinned_at IS NULL",
+            id,
+            pinned_by,
+            pinned_at
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /* Returns false if the message was not pinned */
+    pub async fn unpin(pool: &PgPool, id: Uuid) -> DatabaseResult<bool> {
+        let result = sqlx::query!(
+            "UPDATE messages SET pinned_at = NULL, pinned_by = NULL WHERE id = $1 AND pinned_at IS NOT NULL",
+            id
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn get_by_client_msg_id(
+        pool: &PgPool,
+        chat_id: Uuid,
+        user_id: Uuid,
+        client_msg_id: &str,
+    ) -> DatabaseResult<Option<ModelMessage>> {
+        sqlx::query_as!(
+            ModelMessage,
+            "SELECT * FROM messages WHERE chat_id = $1 AND user_id = $2 AND client_msg_id = $3",
+            chat_id,
+            user_id,
+            client_msg_id
+        )
+        .fetch_optional(pool)
+        .await
+    }
+
+    pub async fn get_cursor(
+        pool: &PgPool,
+        chat_id: Uuid,
+        id: Uuid,
+    ) -> DatabaseResult<HistoryCursor> {
+        sqlx::query_as!(
+            HistoryCursor,
+            "SELECT id, created_at FROM messages WHERE id = $1 AND chat_id = $2",
+            id,
+            chat_id
+        )
+        .fetch_one(pool)
         .await
     }
 
+    /* Latest `limit` messages of a chat, or the ones right before `before` */
     pub async fn get_chat_history(
         pool: &PgPool,
         chat_id: Uuid,
-    ) -> DatabaseResult<Vec<HistoryMessage>> {
+        before: Option<HistoryCursor>,
+        limit: i64,
+    ) -> DatabaseResult<HistoryPage> {
+        let (before_created_at, before_id) = match before {
+            Some(cursor) => (Some(cursor.created_at), Some(cursor.id)),
+            None => (None, None),
+        };
+
+        // One extra row tells whether there is another page
+        let mut messages = sqlx::query_as!(
+            ChatMessage,
+            r#"SELECT messages.id, messages.chat_id, users.username, users.id as user_id, messages.content, messages.created_at, messages.client_msg_id, messages.edited_at, messages.deleted_at,
+                messages.reply_to, quoted_users.username as "quoted_username?", LEFT(quoted.content, $5) as "quoted_content?",
+                (SELECT COALESCE(json_agg(json_build_object('emoji', emoji, 'count', count) ORDER BY first_used, emoji), '[]')
+                    FROM (SELECT emoji, COUNT(*) as count, MIN(created_at) as first_used FROM message_reactions WHERE message_id = messages.id GROUP BY emoji) counts
+                ) as "reactions!: Json<Vec<ReactionCount>>", messages.pinned_at, messages.pinned_by
+            FROM messages
+            INNER JOIN users ON messages.user_id = users.id
+            LEFT JOIN messages quoted ON messages.reply_to = quoted.id
+            LEFT JOIN users quoted_users ON quoted.user_id = quoted_users.id
+            WHERE messages.chat_id = $1
+                AND ($2::timestamptz IS NULL OR (messages.created_at, messages.id) < ($2, $3))
+            ORDER BY messages.created_at DESC, messages.id DESC
+            LIMIT $4"#,
+            chat_id,
+            before_created_at,
+            before_id,
+            limit + 1,
+            QUOTE_LENGTH
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let has_more = messages.len() as i64 > limit;
+        messages.truncate(limit as usize);
+        messages.reverse();
+
+        Ok(HistoryPage { messages, has_more })
+    }
+
+    /* Pinned messages of a chat, most recently pinned first */
+    pub async fn get_pinned(pool: &PgPool, chat_id: Uuid) -> DatabaseResult<Vec<ChatMessage>> {
         sqlx::query_as!(
-            HistoryMessage,
-            "SELECT users.username, users.id as user_id, messages.content, messages.created_at FROM messages
+            ChatMessage,
+            r#"SELECT messages.id, messages.chat_id, users.username, users.id as user_id, messages.content, messages.created_at, messages.client_msg_id, messages.edited_at, messages.deleted_at,
+                messages.reply_to, quoted_users.username as "quoted_username?", LEFT(quoted.content, $2) as "quoted_content?",
+                (SELECT COALESCE(json_agg(json_build_object('emoji', emoji, 'count', count) ORDER BY first_used, emoji), '[]')
+                    FROM (SELECT emoji, COUNT(*) as count, MIN(created_at) as first_used FROM message_reactions WHERE message_id = messages.id GROUP BY emoji) counts
+                ) as "reactions!: Json<Vec<ReactionCount>>", messages.pinned_at, messages.pinned_by
+            FROM messages
             INNER JOIN users ON messages.user_id = users.id
-            WHERE chat_id = $1",
-            chat_id
+            LEFT JOIN messages quoted ON messages.reply_to = quoted.id
+            LEFT JOIN users quoted_users ON quoted.user_id = quoted_users.id
+            WHERE messages.chat_id = $1 AND messages.pinned_at IS NOT NULL
+            ORDER BY messages.pinned_at DESC"#,
+            chat_id,
+            QUOTE_LENGTH
         )
         .fetch_all(pool)
         .await
     }
+
+    /* Replies to a message and all replies to those, oldest first */
+    pub async fn get_thread(
+        pool: &PgPool,
+        chat_id: Uuid,
+        id: Uuid,
+    ) -> DatabaseResult<Vec<ChatMessage>> {
+        sqlx::query_as!(
+            ChatMessage,
+            r#"WITH RECURSIVE thread AS (
+                SELECT id FROM messages WHERE chat_id = $1 AND reply_to = $2
+                UNION
+                SELECT messages.id FROM messages INNER JOIN thread ON messages.reply_to = thread.id
+            )
+            SELECT messages.id, messages.chat_id, users.username, users.id as user_id, messages.content, messages.created_at, messages.client_msg_id, messages.edited_at, messages.deleted_at,
+                messages.reply_to, quoted_users.username as "quoted_username?", LEFT(quoted.content, $3) as "quoted_content?",
+                (SELECT COALESCE(json_agg(json_build_object('emoji', emoji, 'count', count) ORDER BY first_used, emoji), '[]')
+                    FROM (SELECT emoji, COUNT(*) as count, MIN(created_at) as first_used FROM message_reactions WHERE message_id = messages.id GROUP BY emoji) counts
+                ) as "reactions!: Json<Vec<ReactionCount>>", messages.pinned_at, messages.pinned_by
+            FROM messages
+            INNER JOIN users ON messages.user_id = users.id
+            LEFT JOIN messages quoted ON messages.reply_to = quoted.id
+            LEFT JOIN users quoted_users ON quoted.user_id = quoted_users.id
+            WHERE messages.id IN (SELECT id FROM thread)
+            ORDER BY messages.created_at, messages.id"#,
+            chat_id,
+            id,
+            QUOTE_LENGTH
+        )
+        .fetch_all(pool)
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+    use crate::{
+        models::{ChatKind, ModelChat},
+        test_util::{create_user, test_db},
+    };
+
+    async fn create_message(
+        pool: &PgPool,
+        chat_id: Uuid,
+        user_id: Uuid,
+        at: DateTime<Utc>,
+    ) -> Uuid {
+        ModelMessage::create(pool, chat_id, user_id, "hi".into(), at, None, None)
+            .await
+            .unwrap()
+            .unwrap()
+            .id
+    }
+
+    /* Loads every page the way clients do, passing the oldest message they have */
+    async fn walk(pool: &PgPool, chat_id: Uuid, limit: i64) -> Vec<Vec<Uuid>> {
+        let mut pages = Vec::new();
+        let mut before = None;
+        loop {
+            let page = ModelMessage::get_chat_history(pool, chat_id, before.take(), limit)
+                .await
+                .unwrap();
+            let ids: Vec<Uuid> = page.messages.iter().map(ChatMessage::id).collect();
+            if let Some(oldest) = ids.first() {
+                before = Some(
+                    ModelMessage::get_cursor(pool, chat_id, *oldest)
+                        .await
+                        .unwrap(),
+                );
+            }
+            pages.push(ids);
+            if !page.has_more {
+                return pages;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn history_pages_go_back_in_time() {
+        let pool = test_db().await;
+        let user = create_user(&pool, "alice").await;
+        let chat = ModelChat::new(&pool, user.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let start = Utc::now();
+        let mut ids = Vec::new();
+        for minute in 0..5 {
+            ids.push(
+                create_message(&pool, chat.id, user.id, start + Duration::minutes(minute)).await,
+            );
+        }
+
+        let pages = walk(&pool, chat.id, 2).await;
+
+        // Newest page first, every page in chronological order
+        assert_eq!(
+            pages,
+            vec![ids[3..5].to_vec(), ids[1..3].to_vec(), ids[0..1].to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn history_cursor_breaks_timestamp_ties_by_id() {
+        let pool = test_db().await;
+        let user = create_user(&pool, "alice").await;
+        let chat = ModelChat::new(&pool, user.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let at = Utc::now();
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            ids.push(create_message(&pool, chat.id, user.id, at).await);
+        }
+        ids.sort();
+
+        let pages = walk(&pool, chat.id, 1).await;
+
+        assert_eq!(pages.concat(), ids.into_iter().rev().collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn history_page_without_more_messages() {
+        let pool = test_db().await;
+        let user = create_user(&pool, "alice").await;
+        let chat = ModelChat::new(&pool, user.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let id = create_message(&pool, chat.id, user.id, Utc::now()).await;
+
+        let page = ModelMessage::get_chat_history(&pool, chat.id, None, 1)
+            .await
+            .unwrap();
+
+        assert!(!page.has_more);
+        assert_eq!(
+            page.messages
+                .iter()
+                .map(ChatMessage::id)
+                .collect::<Vec<_>>(),
+            vec![id]
+        );
+    }
+
+    #[tokio::test]
+    async fn history_cursor_belongs_to_the_chat() {
+        let pool = test_db().await;
+        let user = create_user(&pool, "alice").await;
+        let chat = ModelChat::new(&pool, user.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let other = ModelChat::new(&pool, user.id, ChatKind::Group)
+            .await
+            .unwrap();
+        let id = create_message(&pool, other.id, user.id, Utc::now()).await;
+
+        assert!(matches!(
+            ModelMessage::get_cursor(&pool, chat.id, id).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+    }
 }
@@ -0,0 +1,162 @@
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::DatabaseResult;
+
+/* Live connections of users to chats */
+pub struct ModelPresence;
+
+/* A user whose connection to a chat was reclaimed from a dead instance */
+pub struct ReclaimedPresence {
+    pub chat_id: Uuid,
+    pub user_id: Uuid,
+}
+
+impl ModelPresence {
+    /* Returns the user's connections to the chat including the new one, None if they aren't a member */
+    pub async fn connect(
+        pool: &PgPool,
+        connection_id: Uuid,
+        chat_id: Uuid,
+        user_id: Uuid,
+        instance_id: &str,
+    ) -> DatabaseResult<Option<i64>> {
+        let mut tx = pool.begin().await?;
+        if !Self::lock(&mut tx, chat_id, user_id).await? {
+            return Ok(None);
+        }
+
+        sqlx::query!(
+            "INSERT INTO presence (connection_id, chat_id, user_id, instance_id) VALUES ($1, $2, $3, $4)",
+            connection_id,
+            chat_id,
+            user_id,
+            instance_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let connections = Self::count_connections(&mut tx, chat_id, user_id).await?;
+        tx.commit().await?;
+
+        Ok(Some(connections))
+    }
+
+    /* Returns the user's remaining connections to the chat, None if the connection was already removed */
+    pub async fn disconnect(
+        pool: &PgPool,
+        connection_id: Uuid,
+        chat_id: Uuid,
+        user_id: Uuid,
+    ) -> DatabaseResult<Option<i64>> {
+        let mut tx = pool.begin().await?;
+        // The user may have left the chat already, there is nothing to race with then
+        Self::lock(&mut tx, chat_id, user_id).await?;
+
+        let result = sqlx::query!(
+            "DELETE FROM presence WHERE connection_id = $1",
+            connection_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        let connections = Self::count_connections(&mut tx, chat_id, user_id).await?;
+        tx.commit().await?;
+
+        Ok(Some(connections))
+    }
+
+    /* Connections a user has open to a chat on any server instance */
+    pub async fn get_connections(
+        pool: &PgPool,
+        chat_id: Uuid,
+        user_id: Uuid,
+    ) -> DatabaseResult<i64> {
+        let mut tx = pool.begin().await?;
+        Self::lock(&mut tx, chat_id, user_id).await?;
+        let connections = Self::count_connections(&mut tx, chat_id, user_id).await?;
+        tx.commit().await?;
+
+        Ok(connections)
+    }
+
+    /* Connections of an instance can't outlive it, clears what it left behind */
+    pub async fn delete_for_instance(pool: &PgPool, instance_id: &str) -> DatabaseResult<u64> {
+        let result = sqlx::query!("DELETE FROM presence WHERE instance_id = $1", instance_id)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn heartbeat(
+        pool: &PgPool,
+        instance_id: &str,
+        at: DateTime<Utc>,
+    ) -> DatabaseResult<()> {
+        sqlx::query!(
+            "INSERT INTO instances (instance_id, heartbeat_at) VALUES ($1, $2)
+            ON CONFLICT (instance_id) DO UPDATE SET heartbeat_at = $2",
+            instance_id,
+            at
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /* Deletes instances without a heartbeat since `stale_before` together with their connections */
+    pub async fn reclaim(
+        pool: &PgPool,
+        stale_before: DateTime<Utc>,
+    ) -> DatabaseResult<Vec<ReclaimedPresence>> {
+        // Rows of instances that never sent a heartbeat are reclaimed as well
+        sqlx::query_as!(
+            ReclaimedPresence,
+            "WITH dead AS (DELETE FROM instances WHERE heartbeat_at < $1 RETURNING instance_id)
+            DELETE FROM presence
+                WHERE instance_id IN (SELECT instance_id FROM dead)
+                    OR instance_id NOT IN (SELECT instance_id FROM instances)
+            RETURNING chat_id, user_id",
+            stale_before
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    /* Serialises the connection changes of a user to a chat on their membership row, false if there is none */
+    async fn lock(
+        tx: &mut Transaction<'_, Postgres>,
+        chat_id: Uuid,
+        user_id: Uuid,
+    ) -> DatabaseResult<bool> {
+        let row = sqlx::query!(
+            "SELECT 1 as locked FROM chat_user WHERE chat_id = $1 AND user_id = $2 FOR UPDATE",
+            chat_id,
+            user_id
+        )
+        .fetch_optional(&mut **tx)
+        .await?;
+
+        Ok(row.is_some())
+    }
+
+    async fn count_connections(
+        tx: &mut Transaction<'_, Postgres>,
+        chat_id: Uuid,
+        user_id: Uuid,
+    ) -> DatabaseResult<i64> {
+        let row = sqlx::query!(
+            r#"SELECT COUNT(*) as "count!" FROM presence WHERE chat_id = $1 AND user_id = $2"#,
+            chat_id,
+            user_id
+        )
+        .fetch_one(&mut **tx)
+        .await?;
+
+        Ok(row.count)
+    }
+}
@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::DatabaseResult;
+
+/* Number of users who reacted to a message with an emoji */
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+}
+
+pub struct ModelReaction;
+
+impl ModelReaction {
+    /* Returns false if the user already reacted with this emoji */
+    pub async fn create(
+        pool: &PgPool,
+        message_id: Uuid,
+        user_id: Uuid,
+        emoji: &str,
+    ) -> DatabaseResult<bool> {
+        let result = sqlx::query!(
+            "INSERT INTO message_reactions (message_id, user_id, emoji) VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING",
+            message_id,
+            user_id,
+            emoji
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /* Returns false if there was no such reaction */
+    pub async fn delete(
+        pool: &PgPool,
+        message_id: Uuid,
+        user_id: Uuid,
+        emoji: &str,
+    ) -> DatabaseResult<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM message_reactions WHERE message_id = $1 AND user_id = $2 AND emoji = $3",
+            message_id,
+            user_id,
+            emoji
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /* Emojis in the order they were first used on the message */
+    pub async fn get_counts(pool: &PgPool, message_id: Uuid) -> DatabaseResult<Vec<ReactionCount>> {
+        sqlx::query_as!(
+            ReactionCount,
+            r#"SELECT emoji, COUNT(*) as "count!" FROM message_reactions
+            WHERE message_id = $1
+            GROUP BY emoji
+            ORDER BY MIN(created_at), emoji"#,
+            message_id
+        )
+        .fetch_all(pool)
+        .await
+    }
+}
@@ -0,0 +1,120 @@
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::DatabaseResult;
+
+/* Session structure in a Sessions table, one per logged in device */
+#[derive(Debug, Clone)]
+pub struct ModelSession {
+    pub token: Uuid,
+    pub user_id: Uuid,
+    pub device: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub id: Uuid,
+    // Only ever matched on in queries
+    #[allow(dead_code)]
+    pub previous_token: Option<Uuid>,
+}
+
+impl ModelSession {
+    pub async fn create(
+        pool: &PgPool,
+        user_id: Uuid,
+        device: Option<String>,
+        ttl: Duration,
+    ) -> DatabaseResult<ModelSession> {
+        let created_at = Utc::now();
+        sqlx::query_as!(
+            ModelSession,
+            "INSERT INTO sessions (token, user_id, device, created_at, expires_at) VALUES ($1, $2, $3, $4, $5) RETURNING *",
+            Uuid::new_v4(),
+            user_id,
+            device,
+            created_at,
+            created_at + ttl
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    pub async fn get_by_token(pool: &PgPool, token: Uuid) -> DatabaseResult<ModelSession> {
+        sqlx::query_as!(
+            ModelSession,
+            "SELECT * FROM sessions WHERE token = $1",
+            token
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    pub async fn get_by_previous_token(pool: &PgPool, token: Uuid) -> DatabaseResult<ModelSession> {
+        sqlx::query_as!(
+            ModelSession,
+            "SELECT * FROM sessions WHERE previous_token = $1",
+            token
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    /* Replaces the token of an active session and extends its expiry */
+    pub async fn rotate(pool: &PgPool, token: Uuid, ttl: Duration) -> DatabaseResult<ModelSession> {
+        sqlx::query_as!(
+            ModelSession,
+            "UPDATE sessions SET previous_token = token, token = $1, expires_at = $2
+                WHERE token = $3 AND revoked_at IS NULL RETURNING *",
+            Uuid::new_v4(),
+            Utc::now() + ttl,
+            token
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    pub async fn get_active_for_user(
+        pool: &PgPool,
+        user_id: Uuid,
+    ) -> DatabaseResult<Vec<ModelSession>> {
+        sqlx::query_as!(
+            ModelSession,
+            "SELECT * FROM sessions
+                WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > $2
+                ORDER BY created_at DESC",
+            user_id,
+            Utc::now()
+        )
+        .fetch_all(pool)
+        .await
+    }
+
+    pub async fn revoke(pool: &PgPool, id: Uuid) -> DatabaseResult<()> {
+        sqlx::query!(
+            "UPDATE sessions SET revoked_at = $1 WHERE id = $2 AND revoked_at IS NULL",
+            Utc::now(),
+            id
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn revoke_all(pool: &PgPool, user_id: Uuid) -> DatabaseResult<()> {
+        sqlx::query!(
+            "UPDATE sessions SET revoked_at = $1 WHERE user_id = $2 AND revoked_at IS NULL",
+            Utc::now(),
+            user_id
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+}
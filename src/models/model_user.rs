@@ -3,9 +3,7 @@ use sqlx::{FromRow, PgPool};
 
 use uuid::Uuid;
 
-use crate::login::Login;
-
-use super::{DatabaseResult, PostgresResult};
+use super::DatabaseResult;
 
 #[derive(Debug, FromRow, Deserialize, Serialize, Eq, PartialEq, Clone)]
 #[allow(non_snake_case)]
@@ -13,7 +11,6 @@ pub struct ModelUser {
     pub id: Uuid,
     pub username: String,
     pub password: String,
-    pub token: Uuid,
     // #[serde(deserialize_with = "time::serde::deserialize")]
     // pub created_at: OffsetDateTime,
     // #[serde(rename = "updatedAt")]
@@ -26,18 +23,33 @@ impl Default for ModelUser {
             id: Uuid::nil(),
             username: String::from(""),
             password: String::from(""),
-            token: Uuid::nil(),
         }
     }
 }
 
 impl ModelUser {
-    pub async fn get(pool: &PgPool, login: Login) -> DatabaseResult<ModelUser> {
+    /* Expects an already hashed password */
+    pub async fn create(
+        pool: &PgPool,
+        username: &str,
+        password: &str,
+    ) -> DatabaseResult<ModelUser> {
+        sqlx::query_as!(
+            ModelUser,
+            "INSERT INTO users (id, username, password) VALUES ($1, $2, $3) RETURNING *",
+            Uuid::new_v4(),
+            username,
+            password
+        )
+        .fetch_one(pool)
+        .await
+    }
+
+    pub async fn get_by_username(pool: &PgPool, username: &str) -> DatabaseResult<ModelUser> {
         let user = sqlx::query_as!(
             ModelUser,
-            "SELECT * FROM users WHERE password = $1 AND username = $2",
-            login.password,
-            login.username
+            "SELECT * FROM users WHERE username = $1",
+            username
         )
         .fetch_one(pool)
         .await?;
@@ -45,15 +57,16 @@ impl ModelUser {
         Ok(user)
     }
 
-    pub async fn get_by_token(pool: &PgPool, token: Uuid) -> DatabaseResult<ModelUser> {
-        let user = sqlx::query_as!(ModelUser, "SELECT * FROM users WHERE token = $1", token,)
-            .fetch_one(pool)
+    /* Expects an already hashed password */
+    pub async fn update_password(pool: &PgPool, id: Uuid, password: &str) -> DatabaseResult<()> {
+        sqlx::query!("UPDATE users SET password = $1 WHERE id = $2", password, id)
+            .execute(pool)
             .await?;
 
-        Ok(user)
+        Ok(())
     }
 
-    pub async fn get_by_id(pool: &PgPool, id: Uuid) -> PostgresResult<ModelUser> {
+    pub async fn get_by_id(pool: &PgPool, id: Uuid) -> DatabaseResult<ModelUser> {
         let user = sqlx::query_as!(ModelUser, "SELECT * FROM users WHERE id = $1", id,)
             .fetch_one(pool)
             .await?;
@@ -63,13 +76,12 @@ impl ModelUser {
         Ok(user)
     }
 
-    pub async fn get_users_in_chat(pool: &PgPool, chat_id: Uuid) -> DatabaseResult<Vec<ModelUser>> {
+    /* Users with a live connection to the chat */
+    pub async fn get_online_users(pool: &PgPool, chat_id: Uuid) -> DatabaseResult<Vec<ModelUser>> {
         sqlx::query_as!(
             ModelUser,
             "SELECT users.* FROM users
-                JOIN chat_user AS cu
-                ON cu.user_id = users.id
-                WHERE cu.chat_id = $1",
+                WHERE users.id IN (SELECT user_id FROM presence WHERE chat_id = $1)",
             chat_id
         )
         .fetch_all(pool)
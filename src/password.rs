@@ -0,0 +1,95 @@
+use std::sync::LazyLock;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use subtle::ConstantTimeEq;
+use tokio::task::{self, JoinError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum PasswordError {
+    #[error("PasswordError: {0}")]
+    HashError(argon2::password_hash::Error),
+    #[error("PasswordError: {0}")]
+    TaskError(#[from] JoinError),
+}
+
+impl From<argon2::password_hash::Error> for PasswordError {
+    fn from(e: argon2::password_hash::Error) -> Self {
+        Self::HashError(e)
+    }
+}
+
+/* Result of checking a password against the value stored in `users.password` */
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    Valid,
+    // Password matched a legacy plaintext row and has to be rehashed
+    ValidLegacy,
+    Invalid,
+}
+
+// Checked against when the user doesn't exist, so unknown usernames take as long as wrong passwords
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| hash(SaltString::generate(&mut OsRng).as_str()).unwrap());
+
+/* Argon2 takes tens of milliseconds of CPU, so it runs on the blocking thread pool */
+pub async fn hash_password(password: String) -> Result<String, PasswordError> {
+    task::spawn_blocking(move || hash(&password)).await?
+}
+
+pub async fn verify_password(
+    password: String,
+    stored: String,
+) -> Result<Verification, PasswordError> {
+    task::spawn_blocking(move || verify(&password, &stored)).await?
+}
+
+/* Does the work of `verify_password` for a user that doesn't exist, never valid */
+pub async fn verify_dummy_password(password: String) -> Result<(), PasswordError> {
+    task::spawn_blocking(move || verify(&password, &DUMMY_HASH)).await??;
+
+    Ok(())
+}
+
+fn hash(password: &str) -> Result<String, PasswordError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+
+    Ok(hash.to_string())
+}
+
+fn verify(password: &str, stored: &str) -> Result<Verification, PasswordError> {
+    // Rows created before hashing was introduced hold the password in plaintext
+    let Ok(hash) = PasswordHash::new(stored) else {
+        // Constant time so the stored password can't be guessed byte by byte
+        return Ok(
+            match bool::from(password.as_bytes().ct_eq(stored.as_bytes())) {
+                true => Verification::ValidLegacy,
+                false => Verification::Invalid,
+            },
+        );
+    };
+
+    match Argon2::default().verify_password(password.as_bytes(), &hash) {
+        Ok(()) => Ok(Verification::Valid),
+        Err(argon2::password_hash::Error::Password) => Ok(Verification::Invalid),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plaintext_passwords_are_still_accepted() {
+        assert_eq!(
+            verify("secret", "secret").unwrap(),
+            Verification::ValidLegacy
+        );
+        assert_eq!(verify("secret", "secreT").unwrap(), Verification::Invalid);
+        assert_eq!(verify("secret", "secret2").unwrap(), Verification::Invalid);
+    }
+}
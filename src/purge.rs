@@ -7,8 +7,19 @@ async fn main() -> anyhow::Result<()> {
     dotenv().ok();
     let pool = db::connect_db().await;
     sqlx::query!("DELETE FROM users").execute(&pool).await?;
+    sqlx::query!("DELETE FROM sessions").execute(&pool).await?;
+    sqlx::query!("DELETE FROM message_reactions")
+        .execute(&pool)
+        .await?;
     sqlx::query!("DELETE FROM messages").execute(&pool).await?;
     sqlx::query!("DELETE FROM chat_user").execute(&pool).await?;
+    sqlx::query!("DELETE FROM presence").execute(&pool).await?;
+    sqlx::query!("DELETE FROM bus_events")
+        .execute(&pool)
+        .await?;
+    sqlx::query!("DELETE FROM chat_invites")
+        .execute(&pool)
+        .await?;
     sqlx::query!("DELETE FROM chats").execute(&pool).await?;
 
     Ok(())
@@ -3,11 +3,13 @@ use dotenv::dotenv;
 mod db;
 
 async fn seed_db(pool: &sqlx::PgPool) -> anyhow::Result<()> {
-    sqlx::query!("INSERT INTO users (id, username, token, password) VALUES ('cc36a1f5-eb49-4552-b159-ce3040c519e0', 'Test1', 'ab36a1f5-eb49-4552-b159-ce3040c519e1', 'pass')").execute(pool).await?;
-    sqlx::query!("INSERT INTO users (id, username, token, password) VALUES ('ac36a1f5-eb49-4552-b159-ce3040c519e0', 'Test2', 'cb36a1f5-eb49-4552-b159-ce3040c519e1', 'pass')").execute(pool).await?;
-    sqlx::query!("INSERT INTO chats (id) VALUES ('d58535ec-fe54-4d30-9808-94af7d6dc1bf')")
-        .execute(pool)
-        .await?;
+    sqlx::query!("INSERT INTO users (id, username, password) VALUES ('cc36a1f5-eb49-4552-b159-ce3040c519e0', 'Test1', 'pass')").execute(pool).await?;
+    sqlx::query!("INSERT INTO users (id, username, password) VALUES ('ac36a1f5-eb49-4552-b159-ce3040c519e0', 'Test2', 'pass')").execute(pool).await?;
+    sqlx::query!("INSERT INTO sessions (token, user_id, expires_at) VALUES ('ab36a1f5-eb49-4552-b159-ce3040c519e1', 'cc36a1f5-eb49-4552-b159-ce3040c519e0', '2100-01-01T00:00:00Z')").execute(pool).await?;
+    sqlx::query!("INSERT INTO sessions (token, user_id, expires_at) VALUES ('cb36a1f5-eb49-4552-b159-ce3040c519e1', 'ac36a1f5-eb49-4552-b159-ce3040c519e0', '2100-01-01T00:00:00Z')").execute(pool).await?;
+    sqlx::query!("INSERT INTO chats (id, created_by) VALUES ('d58535ec-fe54-4d30-9808-94af7d6dc1bf', 'cc36a1f5-eb49-4552-b159-ce3040c519e0')").execute(pool).await?;
+    sqlx::query!("INSERT INTO chat_user (chat_id, user_id, role) VALUES ('d58535ec-fe54-4d30-9808-94af7d6dc1bf', 'cc36a1f5-eb49-4552-b159-ce3040c519e0', 'owner')").execute(pool).await?;
+    sqlx::query!("INSERT INTO chat_user (chat_id, user_id) VALUES ('d58535ec-fe54-4d30-9808-94af7d6dc1bf', 'ac36a1f5-eb49-4552-b159-ce3040c519e0')").execute(pool).await?;
     Ok(())
 }
 
@@ -0,0 +1,154 @@
+use std::{env, fs, ops::Deref, path::PathBuf, str::FromStr, sync::Arc, thread, time};
+
+use chrono::Duration;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    Connection, Executor, PgConnection, PgPool,
+};
+use uuid::Uuid;
+
+use crate::{
+    config::{BusKind, Config, JwtKeys, TokenMode},
+    controller::Controller,
+    models::ModelUser,
+    AppState,
+};
+
+/* A database of its own for a test, dropped again along with it */
+pub struct TestDb {
+    pool: PgPool,
+    name: String,
+    // Connects to the DATABASE_URL database the test database was created from
+    admin_options: PgConnectOptions,
+}
+
+impl Deref for TestDb {
+    type Target = PgPool;
+
+    fn deref(&self) -> &PgPool {
+        &self.pool
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        let options = self.admin_options.clone();
+        let name = self.name.clone();
+        // Drop can't await and the test's runtime may be the one running it, so this gets a thread
+        let dropped = thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(async move {
+                    let mut admin = PgConnection::connect_with(&options).await?;
+                    // Tasks of the test may still hold connections
+                    admin
+                        .execute(format!("DROP DATABASE {} WITH (FORCE)", name).as_str())
+                        .await?;
+                    admin.close().await
+                })
+        })
+        .join();
+
+        if let Ok(Err(e)) = dropped {
+            eprintln!("Failed to drop test database {}: {}", self.name, e);
+        }
+    }
+}
+
+/* Created next to DATABASE_URL with all migrations applied */
+pub async fn test_db() -> TestDb {
+    let url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let options = PgConnectOptions::from_str(&url).expect("DATABASE_URL is invalid");
+    let name = format!("robin_test_{}", Uuid::new_v4().simple());
+
+    let admin = PgPoolOptions::new()
+        .max_connections(1)
+        .connect_with(options.clone())
+        .await
+        .unwrap();
+    admin
+        .execute(format!("CREATE DATABASE {}", name).as_str())
+        .await
+        .unwrap();
+    admin.close().await;
+
+    let pool = PgPoolOptions::new()
+        .max_connections(5)
+        .connect_with(options.clone().database(&name))
+        .await
+        .unwrap();
+    for migration in migrations() {
+        // Without bind parameters the whole file goes out as one simple query
+        pool.execute(fs::read_to_string(migration).unwrap().as_str())
+            .await
+            .unwrap();
+    }
+
+    TestDb {
+        pool,
+        name,
+        admin_options: options,
+    }
+}
+
+/* Migration files ordered by their version, V2 before V10 */
+fn migrations() -> Vec<PathBuf> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("migrations");
+    let mut migrations: Vec<(u32, PathBuf)> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            let version = name.strip_prefix('V')?.split_once("__")?.0.parse().ok()?;
+            Some((version, path))
+        })
+        .collect();
+    migrations.sort();
+
+    migrations.into_iter().map(|(_, path)| path).collect()
+}
+
+pub fn test_config(token_mode: TokenMode) -> Config {
+    Config {
+        port: 0,
+        token_mode,
+        session_ttl: Duration::days(30),
+        handshake_timeout: time::Duration::from_secs(10),
+        history_page_size: 50,
+        instance_id: Uuid::new_v4().to_string(),
+        chat_channel_capacity: 100,
+        outbound_queue_size: 32,
+        bus: BusKind::Memory,
+    }
+}
+
+pub fn jwt_mode() -> TokenMode {
+    TokenMode::Jwt(JwtKeys {
+        algorithm: Algorithm::HS256,
+        encoding: EncodingKey::from_secret(b"test secret"),
+        decoding: DecodingKey::from_secret(b"test secret"),
+        access_ttl: Duration::minutes(15),
+    })
+}
+
+/* The database has to outlive the state */
+pub async fn test_state(token_mode: TokenMode) -> (Arc<AppState>, TestDb) {
+    let db = test_db().await;
+    let config = test_config(token_mode);
+    let controller = Controller::new(db.clone(), &config);
+
+    let state = Arc::new(AppState {
+        config,
+        db: db.clone(),
+        controller,
+    });
+
+    (state, db)
+}
+
+pub async fn create_user(db: &PgPool, username: &str) -> ModelUser {
+    ModelUser::create(db, username, "password").await.unwrap()
+}
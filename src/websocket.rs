@@ -1,163 +1,752 @@
-use anyhow::Error;
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        State, WebSocketUpgrade,
+        Query, State, WebSocketUpgrade,
+    },
+    http::{
+        header::{AUTHORIZATION, SEC_WEBSOCKET_PROTOCOL},
+        HeaderMap,
     },
-    http::StatusCode,
     response::{IntoResponse, Response},
     Error as AxumError,
 };
-use chrono::Utc;
 use futures::stream::{SplitSink, SplitStream, StreamExt};
 use futures::SinkExt;
-use serde_json::{from_str, to_string};
-use sqlx::{Pool, Postgres};
+use serde::Deserialize;
+use serde_json::{from_str, from_value, to_string, Value};
 use std::sync::Arc;
-use tokio::{sync::broadcast::Sender, task::JoinHandle};
+use tokio::sync::{
+    broadcast::{error::RecvError, Receiver},
+    mpsc::{self, error::TrySendError},
+};
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 
 use crate::{
-    app_error::AppError,
-    models::{ModelChat, ModelChatUser, ModelMessage, ModelUser},
-    AppState, RequestMessage, ResponseMessage, User,
+    auth::{self, AuthError, Identity},
+    controller::ControllerError,
+    models::{ChatChanges, ChatError, ChatKind, ChatRole, ModelChat, ModelChatUser, ModelMessage},
+    AppState, Chat, ErrorCode, RequestMessage, ResponseMessage, User,
 };
 
-async fn websocket(ws: WebSocket, state: Arc<AppState>) {
-    websocket_result(ws, state).await.unwrap()
+async fn websocket(
+    ws: WebSocket,
+    state: Arc<AppState>,
+    identity: Option<Identity>,
+    chat_id: Option<Uuid>,
+) {
+    if let Err(e) = websocket_result(ws, state, identity, chat_id).await {
+        tracing::error!("WebSocket connection failed: {}", e);
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 enum WebSocketError {
     #[error(transparent)]
-    JoinError(#[from] ClientReceiverError),
+    ReceiverError(#[from] ClientReceiverError),
     #[error(transparent)]
-    UserError(#[from] sqlx::Error),
+    DatabaseError(#[from] sqlx::Error),
     #[error(transparent)]
-    ChatError(#[from] crate::models::ChatError),
+    ChatError(#[from] ChatError),
     #[error(transparent)]
     ControllerError(#[from] ControllerError),
     #[error(transparent)]
     SenderError(#[from] ClientSenderError),
+    #[error(transparent)]
+    BroadcastError(#[from] RecvError),
+    #[error("Enter a chat first")]
+    NoChatEntered,
+}
+
+impl WebSocketError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            Self::ReceiverError(e) => e.code(),
+            Self::ChatError(e) => chat_error_code(e),
+            Self::ControllerError(e) => e.code(),
+            Self::NoChatEntered => ErrorCode::NotInChat,
+            Self::DatabaseError(_) | Self::SenderError(_) | Self::BroadcastError(_) => {
+                ErrorCode::ServerError
+            }
+        }
+    }
+
+    /* Fatal errors close the connection, the rest are reported to the client */
+    fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Self::ReceiverError(
+                ClientReceiverError::StreamClosed | ClientReceiverError::ReceiveError
+            ) | Self::SenderError(_)
+                | Self::BroadcastError(_)
+        )
+    }
+
+    fn request_id(&self) -> Option<String> {
+        match self {
+            Self::ReceiverError(ClientReceiverError::InvalidMessage { request_id }) => {
+                request_id.clone()
+            }
+            _ => None,
+        }
+    }
+
+    fn to_response(&self, request_id: Option<String>) -> ResponseMessage {
+        error_response(self.code(), self, request_id)
+    }
+}
+
+/* Internal details of server errors stay in the logs */
+fn error_response(
+    code: ErrorCode,
+    error: &dyn std::error::Error,
+    request_id: Option<String>,
+) -> ResponseMessage {
+    let message = match code {
+        ErrorCode::ServerError => String::from("Something went wrong"),
+        _ => error.to_string(),
+    };
+
+    ResponseMessage::Error {
+        code,
+        message,
+        request_id,
+    }
+}
+
+fn chat_error_code(e: &ChatError) -> ErrorCode {
+    match e {
+        ChatError::ChatNotFound => ErrorCode::ChatNotFound,
+        ChatError::DatabaseError(_) => ErrorCode::ServerError,
+    }
 }
 
-async fn websocket_result(ws: WebSocket, state: Arc<AppState>) -> Result<(), WebSocketError> {
+#[derive(thiserror::Error, Debug)]
+enum HandshakeError {
+    #[error("Handshake timed out")]
+    Timeout,
+    #[error("Expected a Join message")]
+    InvalidMessage,
+    #[error(transparent)]
+    ReceiverError(#[from] ClientReceiverError),
+    #[error(transparent)]
+    AuthError(#[from] AuthError),
+}
+
+impl HandshakeError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            Self::Timeout => ErrorCode::HandshakeTimeout,
+            Self::InvalidMessage => ErrorCode::InvalidMessage,
+            Self::ReceiverError(e) => e.code(),
+            Self::AuthError(e) => e.code(),
+        }
+    }
+
+    fn to_response(&self) -> ResponseMessage {
+        error_response(self.code(), self, None)
+    }
+}
+
+async fn websocket_result(
+    ws: WebSocket,
+    state: Arc<AppState>,
+    identity: Option<Identity>,
+    chat_id: Option<Uuid>,
+) -> Result<(), WebSocketError> {
     // Client specific channel
     let (sender, receiver) = ws.split();
     let mut client_receiver = ClientReceiver::new(receiver).await;
-    let mut client_sender = ClientSender::new(sender).await;
-
-    // Broadcast channel
-    let mut broadcast_receiver = state.broadcast_sender.subscribe();
+    let mut client_sender = ClientSender::new(sender, state.config.outbound_queue_size).await;
+
+    let (user, chat_id) = match identity {
+        // Already authorised when the connection was upgraded
+        Some(identity) => (identity.user, chat_id),
+        None => match handshake(&state, &mut client_receiver).await {
+            Ok(joined) => joined,
+            Err(HandshakeError::ReceiverError(ClientReceiverError::StreamClosed)) => return Ok(()),
+            // Tell the client why it is being disconnected
+            Err(e) => {
+                tracing::warn!("rejected join: {}", e);
+                client_sender.send(e.to_response())?;
+                client_sender.close()?;
+                return Ok(());
+            }
+        },
+    };
+    tracing::warn!("{} joined", user.username);
 
-    let RequestMessage::Join { token } = client_receiver.next().await? else {
-        return Err(WebSocketError::JoinError(
-            ClientReceiverError::InvalidMessage,
-        ));
+    let mut connection = Connection {
+        state,
+        user,
+        id: Uuid::new_v4(),
+        chat_id: None,
+        broadcast_receiver: None,
+        chat_role: None,
+        chat_archived: false,
+        client_sender,
     };
 
-    let user = ModelUser::get_by_token(&state.db, token).await?;
-    tracing::warn!("{} joined", user.username);
+    let result = connection.run(chat_id, client_receiver).await;
 
-    let chat_id = ModelChat::get_id()?;
-    state
-        .controller
-        .join_user(chat_id, User::from_model_user(user.clone()))
-        .await?;
+    // Leave the current chat however the connection ended, dropping the
+    // connection covers the paths that don't get here
+    connection.exit_chat().await?;
 
-    let connected_users = ModelUser::get_users_in_chat(&state.db, chat_id)
-        .await?
-        .into_iter()
-        .map(User::from_model_user)
-        .collect::<Vec<_>>();
+    tracing::warn!("left {}", connection.user.username);
 
-    // Send a history of a chat to a newly joined user
-    let chat_history = ModelMessage::get_chat_history(&state.db, chat_id).await?;
+    result
+}
 
-    client_sender
-        .send(ResponseMessage::History {
-            messages: chat_history,
-            users: connected_users,
-        })
-        .await?;
+/* In-band authorisation, the first message has to be a Join sent within the handshake timeout */
+async fn handshake(
+    state: &AppState,
+    client_receiver: &mut ClientReceiver,
+) -> Result<(User, Option<Uuid>), HandshakeError> {
+    let request = tokio::time::timeout(state.config.handshake_timeout, client_receiver.next())
+        .await
+        .map_err(|_| HandshakeError::Timeout)??;
+
+    let RequestMessage::Join { token, chat_id } = request.message else {
+        return Err(HandshakeError::InvalidMessage);
+    };
+
+    let identity = auth::authenticate(state, &token).await?;
+
+    Ok((identity.user, chat_id))
+}
+
+/* State of a single authorised WebSocket connection */
+struct Connection {
+    state: Arc<AppState>,
+    user: User,
+    // Identifies the presence of this connection
+    id: Uuid,
+    // Chat the user is currently in, if any
+    chat_id: Option<Uuid>,
+    // Broadcast channel of the current chat
+    broadcast_receiver: Option<Receiver<ResponseMessage>>,
+    // Role and archived flag of the current chat, kept up to date from its events
+    chat_role: Option<ChatRole>,
+    chat_archived: bool,
+    client_sender: ClientSender,
+}
+
+impl Connection {
+    async fn run(
+        &mut self,
+        chat_id: Option<Uuid>,
+        mut client_receiver: ClientReceiver,
+    ) -> Result<(), WebSocketError> {
+        if let Some(chat_id) = chat_id {
+            if let Err(e) = self.enter_chat(chat_id).await {
+                self.report(e, None).await?;
+            }
+        }
 
-    // Forward messages from broadcast(global) to client specific channel
-    let mut send_task: JoinHandle<Result<(), AppError>> = tokio::spawn(async move {
         loop {
-            let msg = broadcast_receiver.recv().await?;
-            client_sender.send(msg).await?;
+            tokio::select! {
+                // Forward messages from the current chat broadcast to the client
+                message = recv_broadcast(self.broadcast_receiver.as_mut()) => {
+                    let message = match message {
+                        Ok(message) => message,
+                        // The client reads slower than the chat is written to
+                        Err(RecvError::Lagged(missed)) => {
+                            tracing::warn!("{} missed {} events", self.user.username, missed);
+                            if let Some(chat_id) = self.chat_id {
+                                self.resync(chat_id, missed).await?;
+                            }
+                            continue;
+                        }
+                        Err(e) => return Err(e.into()),
+                    };
+                    let own_typing = matches!(
+                        &message,
+                        ResponseMessage::Typing { user, .. } | ResponseMessage::StoppedTyping { user, .. }
+                            if user.id == self.user.id
+                    );
+                    // Left from another connection or removed by an admin
+                    let removed = matches!(
+                        &message,
+                        ResponseMessage::MemberLeft { user, .. } if user.id == self.user.id
+                    );
+                    match &message {
+                        ResponseMessage::RoleChanged { user, role, .. } if user.id == self.user.id => {
+                            self.chat_role = Some(*role);
+                        }
+                        ResponseMessage::ChatUpdated { chat } => self.chat_archived = chat.archived,
+                        _ => {}
+                    }
+                    if !own_typing {
+                        self.client_sender.send(message)?
+                    }
+                    if removed {
+                        self.exit_chat().await?;
+                    }
+                }
+                // Handle requests coming from the client
+                request = client_receiver.next() => match request {
+                    Ok(frame) => {
+                        if let Err(e) = self.handle_request(frame.message).await {
+                            self.report(e, frame.request_id).await?;
+                        }
+                    }
+                    // Client closed the socket, nothing went wrong
+                    Err(ClientReceiverError::StreamClosed) => return Ok(()),
+                    Err(e) => self.report(e.into(), None).await?,
+                },
+            }
+        }
+    }
+
+    /* The missed events may include the user's own MemberLeft, which has to detach them */
+    async fn resync(&mut self, chat_id: Uuid, missed: u64) -> Result<(), WebSocketError> {
+        if ModelChatUser::exists(&self.state.db, chat_id, self.user.id).await? {
+            self.client_sender
+                .send(ResponseMessage::Resync { chat_id, missed })?;
+            return Ok(());
+        }
+
+        self.client_sender.send(ResponseMessage::MemberLeft {
+            chat_id,
+            user: self.user.clone(),
+        })?;
+        self.exit_chat().await
+    }
+
+    /* Sends a non-fatal error to the client, fatal ones end the connection */
+    async fn report(
+        &mut self,
+        e: WebSocketError,
+        request_id: Option<String>,
+    ) -> Result<(), WebSocketError> {
+        if e.is_fatal() {
+            return Err(e);
+        }
+        let request_id = request_id.or_else(|| e.request_id());
+
+        match e.code() {
+            ErrorCode::ServerError => tracing::error!("{} failed: {}", self.user.username, e),
+            _ => tracing::debug!("{} sent a bad request: {}", self.user.username, e),
+        }
+        self.client_sender.send(e.to_response(request_id))?;
+
+        Ok(())
+    }
+
+    async fn handle_request(&mut self, request: RequestMessage) -> Result<(), WebSocketError> {
+        match request {
+            RequestMessage::Message {
+                content,
+                client_msg_id,
+                reply_to,
+            } => {
+                let chat_id = self.chat_id.ok_or(WebSocketError::NoChatEntered)?;
+                let message = self
+                    .state
+                    .controller
+                    .send_message(
+                        chat_id,
+                        self.user.id,
+                        self.user.username.clone(),
+                        content,
+                        client_msg_id.clone(),
+                        reply_to,
+                    )
+                    .await?;
+                self.client_sender.send(ResponseMessage::Ack {
+                    client_msg_id,
+                    message_id: message.id(),
+                    timestamp: message.created_at(),
+                })?;
+            }
+            RequestMessage::CreateChat { kind } => {
+                let chat = self
+                    .state
+                    .controller
+                    .create_chat(self.user.id, kind.unwrap_or(ChatKind::Group))
+                    .await?;
+                self.client_sender
+                    .send(ResponseMessage::ChatCreated { chat })?;
+            }
+            RequestMessage::OpenDirect { user_id } => {
+                let chat = self
+                    .state
+                    .controller
+                    .open_direct(self.user.clone(), user_id)
+                    .await?;
+                self.client_sender
+                    .send(ResponseMessage::DirectOpened { chat })?;
+            }
+            RequestMessage::ListChats => {
+                let chats = ModelChatUser::get_user_chats(&self.state.db, self.user.id)
+                    .await?
+                    .into_iter()
+                    .map(Chat::from_user_chat)
+                    .collect::<Vec<_>>();
+                self.client_sender.send(ResponseMessage::Chats { chats })?;
+            }
+            RequestMessage::JoinChat { chat_id } => {
+                self.state
+                    .controller
+                    .join_chat(chat_id, self.user.clone())
+                    .await?;
+            }
+            RequestMessage::LeaveChat { chat_id } => {
+                self.state
+                    .controller
+                    .leave_chat(chat_id, self.user.clone())
+                    .await?;
+                if self.chat_id == Some(chat_id) {
+                    self.exit_chat().await?;
+                }
+            }
+            RequestMessage::RemoveMember { chat_id, user_id } => {
+                self.state
+                    .controller
+                    .remove_member(chat_id, self.user.id, user_id)
+                    .await?;
+            }
+            RequestMessage::SetRole {
+                chat_id,
+                user_id,
+                role,
+            } => {
+                self.state
+                    .controller
+                    .set_role(chat_id, self.user.clone(), user_id, role)
+                    .await?;
+            }
+            RequestMessage::EnterChat { chat_id } => self.enter_chat(chat_id).await?,
+            RequestMessage::UpdateChat {
+                chat_id,
+                name,
+                topic,
+                avatar_url,
+                archived,
+                public,
+            } => {
+                let changes = ChatChanges {
+                    name,
+                    topic,
+                    avatar_url,
+                    archived,
+                    public,
+                };
+                let chat = self
+                    .state
+                    .controller
+                    .update_chat(chat_id, self.user.id, changes)
+                    .await?;
+                // Connections in the chat get it through the broadcast
+                if self.chat_id != Some(chat_id) {
+                    self.client_sender
+                        .send(ResponseMessage::ChatUpdated { chat })?;
+                }
+            }
+            RequestMessage::LoadHistory { before, limit } => {
+                let chat_id = self.chat_id.ok_or(WebSocketError::NoChatEntered)?;
+                let limit = self.state.config.history_limit(limit);
+                let page =
+                    ModelMessage::get_chat_history(&self.state.db, chat_id, before, limit).await?;
+                self.client_sender.send(ResponseMessage::HistoryPage {
+                    chat_id,
+                    messages: page.messages,
+                    has_more: page.has_more,
+                })?;
+            }
+            RequestMessage::EditMessage {
+                message_id,
+                content,
+            } => {
+                let chat_id = self.chat_id.ok_or(WebSocketError::NoChatEntered)?;
+                self.state
+                    .controller
+                    .edit_message(chat_id, self.user.id, message_id, content)
+                    .await?;
+            }
+            RequestMessage::DeleteMessage { message_id } => {
+                let chat_id = self.chat_id.ok_or(WebSocketError::NoChatEntered)?;
+                self.state
+                    .controller
+                    .delete_message(chat_id, self.user.id, message_id)
+                    .await?;
+            }
+            RequestMessage::LoadThread { message_id } => {
+                let chat_id = self.chat_id.ok_or(WebSocketError::NoChatEntered)?;
+                let messages = self
+                    .state
+                    .controller
+                    .load_thread(chat_id, message_id)
+                    .await?;
+                self.client_sender.send(ResponseMessage::Thread {
+                    chat_id,
+                    message_id,
+                    messages,
+                })?;
+            }
+            RequestMessage::Typing { chat_id } => {
+                if self.chat_id != Some(chat_id) {
+                    return Err(WebSocketError::NoChatEntered);
+                }
+                // Checked against the cached chat state, Typing is too frequent for the database
+                if self.chat_archived {
+                    return Err(ControllerError::ChatArchived.into());
+                }
+                if !self.chat_role.is_some_and(ChatRole::can_post) {
+                    return Err(ControllerError::ReadOnly.into());
+                }
+                self.state.controller.typing(chat_id, self.user.clone());
+            }
+            RequestMessage::MarkRead {
+                chat_id,
+                message_id,
+            } => {
+                self.state
+                    .controller
+                    .mark_read(chat_id, self.user.clone(), message_id)
+                    .await?;
+            }
+            RequestMessage::React { message_id, emoji } => {
+                let chat_id = self.chat_id.ok_or(WebSocketError::NoChatEntered)?;
+                self.state
+                    .controller
+                    .react(chat_id, self.user.id, message_id, emoji)
+                    .await?;
+            }
+            RequestMessage::Unreact { message_id, emoji } => {
+                let chat_id = self.chat_id.ok_or(WebSocketError::NoChatEntered)?;
+                self.state
+                    .controller
+                    .unreact(chat_id, self.user.id, message_id, emoji)
+                    .await?;
+            }
+            RequestMessage::PinMessage { message_id } => {
+                let chat_id = self.chat_id.ok_or(WebSocketError::NoChatEntered)?;
+                self.state
+                    .controller
+                    .pin_message(chat_id, self.user.clone(), message_id)
+                    .await?;
+            }
+            RequestMessage::UnpinMessage { message_id } => {
+                let chat_id = self.chat_id.ok_or(WebSocketError::NoChatEntered)?;
+                self.state
+                    .controller
+                    .unpin_message(chat_id, self.user.id, message_id)
+                    .await?;
+            }
+            // The connection is already authorised
+            RequestMessage::Join { .. } => {
+                return Err(ClientReceiverError::InvalidMessage { request_id: None }.into())
+            }
         }
-    });
-
-    // Receive message from a user and broadcast it to all users
-    let state_clone = state.clone();
-    let user_id = user.id;
-    let username = user.username.clone();
-    let mut recv_task: JoinHandle<Result<(), AppError>> = tokio::spawn(async move {
-        while let RequestMessage::Message { content } = client_receiver.next().await? {
-            state_clone
+
+        Ok(())
+    }
+
+    async fn enter_chat(&mut self, chat_id: Uuid) -> Result<(), WebSocketError> {
+        // Fails with ChatNotFound before the user leaves the current chat
+        let chat = ModelChat::get(&self.state.db, chat_id).await?;
+
+        self.exit_chat().await?;
+        let broadcast_receiver = self
+            .state
+            .controller
+            .connect_user(chat_id, self.id, self.user.clone())
+            .await?;
+        self.chat_id = Some(chat_id);
+        self.broadcast_receiver = Some(broadcast_receiver);
+        self.chat_role = ModelChatUser::get_role(&self.state.db, chat_id, self.user.id).await?;
+        self.chat_archived = chat.archived;
+
+        let connected_users = self.state.controller.online_users(chat_id).await?;
+
+        // Send the latest messages of a chat to a newly joined user
+        let chat_history = ModelMessage::get_chat_history(
+            &self.state.db,
+            chat_id,
+            None,
+            self.state.config.history_page_size,
+        )
+        .await?;
+
+        self.client_sender.send(ResponseMessage::History {
+            chat_id,
+            messages: chat_history.messages,
+            has_more: chat_history.has_more,
+            users: connected_users,
+        })?;
+
+        Ok(())
+    }
+
+    async fn exit_chat(&mut self) -> Result<(), WebSocketError> {
+        // Unsubscribe first so the chat channel can be dropped if we were the last member
+        self.broadcast_receiver = None;
+
+        if let Some(chat_id) = self.chat_id {
+            self.state
                 .controller
-                .send_message(chat_id, user_id, username.clone(), content.clone())
+                .disconnect_user(chat_id, self.id, self.user.clone())
                 .await?;
+            // Kept until the presence is gone so dropping the connection retries
+            self.chat_id = None;
         }
+
         Ok(())
-    });
+    }
+}
 
-    /* If receiver task finishes - abort sender task and vice versa */
-    tokio::select! {
-        _ = (&mut send_task) => recv_task.abort(),
-        _ = (&mut recv_task) => send_task.abort(),
-    };
+/* Removes the presence when the connection task returned early or panicked */
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.broadcast_receiver = None;
+
+        if let Some(chat_id) = self.chat_id.take() {
+            let state = self.state.clone();
+            let user = self.user.clone();
+            let id = self.id;
+            tokio::spawn(async move {
+                if let Err(e) = state.controller.disconnect_user(chat_id, id, user).await {
+                    tracing::error!("Failed to remove presence of connection {}: {}", id, e);
+                }
+            });
+        }
+    }
+}
 
-    state
-        .controller
-        .remove_user(chat_id, User::from_model_user(user.clone()))
-        .await?;
+async fn recv_broadcast(
+    receiver: Option<&mut Receiver<ResponseMessage>>,
+) -> Result<ResponseMessage, RecvError> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        // Not in a chat yet, nothing to forward
+        None => std::future::pending().await,
+    }
+}
+
+// Sub-protocol a client offers along with its token as `Sec-WebSocket-Protocol: bearer, <token>`
+const BEARER_PROTOCOL: &str = "bearer";
+
+#[derive(Deserialize, Debug)]
+pub struct WebSocketParams {
+    token: Option<String>,
+    chat_id: Option<Uuid>,
+}
 
-    tracing::warn!("left {}", user.username);
+/* Token from the Authorization header, the Sec-WebSocket-Protocol header or the query string */
+fn upgrade_token(
+    headers: &HeaderMap,
+    params: &WebSocketParams,
+) -> Result<Option<(String, bool)>, AuthError> {
+    if let Some(token) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        return Ok(Some((token.trim().to_string(), false)));
+    }
 
-    Ok(())
+    if let Some(protocols) = headers
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+    {
+        let mut protocols = protocols.split(',').map(str::trim);
+        if protocols.any(|protocol| protocol == BEARER_PROTOCOL) {
+            // Offering the protocol without a token is a failed login, not an anonymous one
+            return match protocols.next() {
+                Some(token) if !token.is_empty() => Ok(Some((token.to_string(), true))),
+                _ => Err(AuthError::MissingToken),
+            };
+        }
+    }
+
+    Ok(params.token.clone().map(|token| (token, false)))
 }
 
+/* Clients either authorise the upgrade request itself or send a Join message right after it */
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| websocket(socket, state))
+    Query(params): Query<WebSocketParams>,
+    headers: HeaderMap,
+) -> Result<Response, AuthError> {
+    let (ws, identity) = match upgrade_token(&headers, &params)? {
+        Some((token, from_protocol)) => {
+            let identity = auth::authenticate(&state, &token).await?;
+            // The handshake fails on the client unless we pick one of the offered protocols
+            let ws = match from_protocol {
+                true => ws.protocols([BEARER_PROTOCOL]),
+                false => ws,
+            };
+            (ws, Some(identity))
+        }
+        None => (ws, None),
+    };
+
+    Ok(ws
+        .on_upgrade(move |socket| websocket(socket, state, identity, params.chat_id))
+        .into_response())
 }
 
+/* Queues messages for a task writing them to the socket, a client that lets its queue fill up is disconnected */
 struct ClientSender {
-    sender: SplitSink<WebSocket, Message>,
+    queue: mpsc::Sender<Message>,
+    writer: JoinHandle<()>,
 }
 
 #[derive(thiserror::Error, Debug)]
 enum ClientSenderError {
     #[error("SendError")]
     SendError,
-}
-
-impl From<AxumError> for ClientSenderError {
-    fn from(e: AxumError) -> Self {
-        Self::SendError
-    }
+    #[error("Outbound queue is full")]
+    QueueFull,
 }
 
 impl From<serde_json::Error> for ClientSenderError {
-    fn from(e: serde_json::Error) -> Self {
+    fn from(_: serde_json::Error) -> Self {
         Self::SendError
     }
 }
 
 impl ClientSender {
-    async fn new(sender: SplitSink<WebSocket, Message>) -> Self {
-        Self { sender }
+    async fn new(mut sender: SplitSink<WebSocket, Message>, queue_size: usize) -> Self {
+        let (queue, mut outbound) = mpsc::channel(queue_size);
+
+        let writer = tokio::spawn(async move {
+            while let Some(message) = outbound.recv().await {
+                let close = matches!(message, Message::Close(_));
+                if let Err(e) = sender.send(message).await {
+                    tracing::debug!("Failed to write to socket: {}", e);
+                    return;
+                }
+                if close {
+                    return;
+                }
+            }
+        });
+
+        Self { queue, writer }
     }
 
-    async fn send(&mut self, message: ResponseMessage) -> Result<(), ClientSenderError> {
-        self.sender
-            .send(Message::Text(to_string(&message)?))
-            .await?;
-        Ok(())
+    fn send(&mut self, message: ResponseMessage) -> Result<(), ClientSenderError> {
+        self.enqueue(Message::Text(to_string(&message)?))
+    }
+
+    fn close(&mut self) -> Result<(), ClientSenderError> {
+        self.enqueue(Message::Close(None))
+    }
+
+    /* Never waits on a slow client, dropping the socket instead so the connection loop isn't stalled */
+    fn enqueue(&mut self, message: Message) -> Result<(), ClientSenderError> {
+        match self.queue.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.writer.abort();
+                Err(ClientSenderError::QueueFull)
+            }
+            Err(TrySendError::Closed(_)) => Err(ClientSenderError::SendError),
+        }
     }
 }
 
@@ -167,102 +756,174 @@ struct ClientReceiver {
 
 #[derive(thiserror::Error, Debug)]
 enum ClientReceiverError {
-    #[error("ClientReceiverError: invalid message type")]
-    InvalidMessage,
+    #[error("Unknown or invalid message")]
+    InvalidMessage { request_id: Option<String> },
+    #[error("Message is not valid JSON")]
+    MalformedJson,
     #[error("ClientReceiverError: stream got closed unexpectedly")]
     StreamClosed,
     #[error("ClientReceiverError: receive error")]
     ReceiveError,
 }
 
+impl ClientReceiverError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            Self::InvalidMessage { .. } => ErrorCode::InvalidMessage,
+            Self::MalformedJson => ErrorCode::MalformedJson,
+            Self::StreamClosed | Self::ReceiveError => ErrorCode::ServerError,
+        }
+    }
+}
+
 impl From<AxumError> for ClientReceiverError {
-    fn from(e: AxumError) -> Self {
+    fn from(_: AxumError) -> Self {
         Self::ReceiveError
     }
 }
 
 impl From<serde_json::Error> for ClientReceiverError {
-    fn from(e: serde_json::Error) -> Self {
-        Self::InvalidMessage
+    fn from(_: serde_json::Error) -> Self {
+        Self::MalformedJson
     }
 }
 
+/* Request as sent by a client, `request_id` is echoed back in errors it causes */
+struct RequestFrame {
+    request_id: Option<String>,
+    message: RequestMessage,
+}
+
 impl ClientReceiver {
     async fn new(receiver: SplitStream<WebSocket>) -> Self {
         Self { receiver }
     }
 
-    async fn next(&mut self) -> Result<RequestMessage, ClientReceiverError> {
-        let receive_event = self.receiver.next().await;
-
-        match receive_event {
-            // If text -> parse
-            Some(Ok(Message::Text(text))) => Ok(from_str(&text)?),
-            // If not text -> InvalidMessage
-            Some(Ok(_)) => Err(ClientReceiverError::InvalidMessage),
-            // If receive_event is Error -> ReceiveError
-            Some(Err(_)) => Err(ClientReceiverError::ReceiveError),
-            // If receive_event is None -> StreamClosed
-            None => Err(ClientReceiverError::StreamClosed),
+    async fn next(&mut self) -> Result<RequestFrame, ClientReceiverError> {
+        loop {
+            let receive_event = self.receiver.next().await;
+
+            return match receive_event {
+                // If text -> parse
+                Some(Ok(Message::Text(text))) => parse_frame(&text),
+                // Pings are answered by axum itself
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                // If close -> StreamClosed
+                Some(Ok(Message::Close(_))) => Err(ClientReceiverError::StreamClosed),
+                // If not text -> InvalidMessage
+                Some(Ok(_)) => Err(ClientReceiverError::InvalidMessage { request_id: None }),
+                // If receive_event is Error -> ReceiveError
+                Some(Err(_)) => Err(ClientReceiverError::ReceiveError),
+                // If receive_event is None -> StreamClosed
+                None => Err(ClientReceiverError::StreamClosed),
+            };
         }
     }
 }
 
-pub struct Controller {
-    db: Pool<Postgres>,
-    broadcast_sender: Sender<ResponseMessage>,
+fn parse_frame(text: &str) -> Result<RequestFrame, ClientReceiverError> {
+    let value: Value = from_str(text)?;
+    let request_id = value
+        .get("request_id")
+        .and_then(Value::as_str)
+        .map(String::from);
+
+    match from_value(value) {
+        Ok(message) => Ok(RequestFrame {
+            request_id,
+            message,
+        }),
+        Err(_) => Err(ClientReceiverError::InvalidMessage { request_id }),
+    }
 }
 
-#[derive(thiserror::Error, Debug)]
-enum ControllerError {
-    // Chat error will become DatabaseError after implementing the chat creating logic
-    #[error(transparent)]
-    ChatError(#[from] crate::models::ChatError),
-    #[error(transparent)]
-    DatabaseError(#[from] sqlx::Error),
-    #[error(transparent)]
-    BroadcastError(#[from] tokio::sync::broadcast::error::SendError<ResponseMessage>),
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderName;
 
-impl Controller {
-    pub fn new(db: Pool<Postgres>, broadcast_sender: Sender<ResponseMessage>) -> Self {
-        Self {
-            db,
-            broadcast_sender,
+    fn error_message(response: ResponseMessage) -> (ErrorCode, String) {
+        match response {
+            ResponseMessage::Error { code, message, .. } => (code, message),
+            _ => panic!("expected an error"),
         }
     }
 
-    async fn join_user(&self, chat_id: Uuid, user: User) -> Result<(), ControllerError> {
-        ModelChatUser::create(&self.db, chat_id, user.id).await?;
+    #[test]
+    fn handshake_server_errors_are_masked() {
+        let error = HandshakeError::AuthError(AuthError::DatabaseError(sqlx::Error::PoolTimedOut));
 
-        // Send message to all users that a new user has joined
-        self.broadcast_sender.send(ResponseMessage::Join { user })?;
+        let (code, message) = error_message(error.to_response());
 
-        Ok(())
+        assert_eq!(code, ErrorCode::ServerError);
+        assert_eq!(message, "Something went wrong");
     }
 
-    async fn remove_user(&self, chat_id: Uuid, user: User) -> Result<(), ControllerError> {
-        ModelChatUser::delete(&self.db, chat_id, user.id).await?;
-        self.broadcast_sender
-            .send(ResponseMessage::Leave { user })?;
+    #[test]
+    fn handshake_client_errors_are_explained() {
+        let error = HandshakeError::AuthError(AuthError::TokenExpired);
 
-        Ok(())
+        let (code, message) = error_message(error.to_response());
+
+        assert_eq!(code, ErrorCode::TokenExpired);
+        assert_eq!(message, "Token has expired");
     }
 
-    async fn send_message(
-        &self,
-        chat_id: Uuid,
-        id: Uuid,
-        username: String,
-        content: String,
-    ) -> Result<(), ControllerError> {
-        ModelMessage::create(&self.db, chat_id, id, content.clone(), Utc::now()).await?;
+    fn upgrade_headers(headers: &[(HeaderName, &str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.parse().unwrap()))
+            .collect()
+    }
 
-        self.broadcast_sender.send(ResponseMessage::Message {
-            username: username.clone(),
-            content,
-        })?;
+    fn params(token: Option<&str>) -> WebSocketParams {
+        WebSocketParams {
+            token: token.map(str::to_string),
+            chat_id: None,
+        }
+    }
 
-        Ok(())
+    #[test]
+    fn upgrade_token_from_authorization_header() {
+        let headers = upgrade_headers(&[(AUTHORIZATION, "Bearer header-token")]);
+
+        let token = upgrade_token(&headers, &params(Some("query-token"))).unwrap();
+
+        assert_eq!(token, Some(("header-token".to_string(), false)));
+    }
+
+    #[test]
+    fn upgrade_token_from_bearer_subprotocol() {
+        let headers = upgrade_headers(&[(SEC_WEBSOCKET_PROTOCOL, "bearer, protocol-token")]);
+
+        let token = upgrade_token(&headers, &params(Some("query-token"))).unwrap();
+
+        assert_eq!(token, Some(("protocol-token".to_string(), true)));
+    }
+
+    #[test]
+    fn upgrade_token_from_query() {
+        let headers = upgrade_headers(&[(SEC_WEBSOCKET_PROTOCOL, "chat")]);
+
+        assert_eq!(
+            upgrade_token(&headers, &params(Some("query-token"))).unwrap(),
+            Some(("query-token".to_string(), false))
+        );
+        assert_eq!(upgrade_token(&headers, &params(None)).unwrap(), None);
+    }
+
+    #[test]
+    fn bearer_subprotocol_without_token_is_rejected() {
+        for protocols in ["bearer", "bearer, ", "chat, bearer"] {
+            let headers = upgrade_headers(&[(SEC_WEBSOCKET_PROTOCOL, protocols)]);
+
+            let result = upgrade_token(&headers, &params(Some("query-token")));
+
+            assert!(
+                matches!(result, Err(AuthError::MissingToken)),
+                "{}",
+                protocols
+            );
+        }
     }
 }